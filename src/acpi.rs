@@ -0,0 +1,259 @@
+use alloc::vec::Vec;
+use core::ptr;
+
+use multiboot2::BootInformation;
+
+use memory::{self, IoMapping, MemoryType, PhysicalAddress};
+
+// Every table starts with a header of this size: signature, length, revision, checksum, OEM ID,
+// OEM table ID, OEM revision, creator ID and creator revision.
+const HEADER_SIZE: usize = 36;
+const LENGTH_OFFSET: usize = 4;
+
+// MADT entry types.
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+
+// Set in a local APIC entry's flags if the processor can be used.
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+fn read_u8(bytes: &[u8], offset: usize) -> u8 {
+  bytes[offset]
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+  assert!(offset + 2 <= bytes.len());
+  unsafe { ptr::read_unaligned(bytes.as_ptr().offset(offset as isize) as *const u16) }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+  assert!(offset + 4 <= bytes.len());
+  unsafe { ptr::read_unaligned(bytes.as_ptr().offset(offset as isize) as *const u32) }
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+  assert!(offset + 8 <= bytes.len());
+  unsafe { ptr::read_unaligned(bytes.as_ptr().offset(offset as isize) as *const u64) }
+}
+
+// Maps the whole of the table at physical, checking that its bytes add up to 0 as they should.
+fn map_table(physical: PhysicalAddress) -> Option<IoMapping<[u8]>> {
+  let length = {
+    let header = memory::controller().ioremap_slice::<u8>(physical, HEADER_SIZE, MemoryType::WriteBack)?;
+    read_u32(&header, LENGTH_OFFSET) as usize
+  };
+  if length < HEADER_SIZE {
+    return None;
+  }
+  let table = memory::controller().ioremap_slice::<u8>(physical, length, MemoryType::WriteBack)?;
+  if table.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+    println!("acpi: table at {:#x} has a bad checksum", physical);
+    return None;
+  }
+  Some(table)
+}
+
+// The root table, found through the RSDP that the boot loader passes on. ACPI 2.0 and later have
+// an XSDT with 64-bit pointers to the other tables, and earlier versions an RSDT with 32-bit ones.
+pub struct Acpi {
+  root: PhysicalAddress,
+  entry_size: usize
+}
+
+impl Acpi {
+  pub fn new(boot_info: &BootInformation) -> Option<Acpi> {
+    if let Some(rsdp) = boot_info.rsdp_v2_tag() {
+      if rsdp.xsdt_address() != 0 {
+        return Some(Acpi { root: rsdp.xsdt_address() as PhysicalAddress, entry_size: 8 });
+      }
+    }
+    boot_info.rsdp_v1_tag().map(|rsdp| Acpi { root: rsdp.rsdt_address() as PhysicalAddress, entry_size: 4 })
+  }
+
+  pub fn find_table(&self, signature: &[u8; 4]) -> Option<IoMapping<[u8]>> {
+    let root = map_table(self.root)?;
+    let entry_count = (root.len() - HEADER_SIZE) / self.entry_size;
+    for i in 0..entry_count {
+      let offset = HEADER_SIZE + i * self.entry_size;
+      let address = if self.entry_size == 8 { read_u64(&root, offset) } else { read_u32(&root, offset) as u64 };
+      // Only the signature is needed to tell whether this is the right table. The mapping has to
+      // outlive the controller's guard, as dropping it locks the controller again.
+      let table_signature = memory::controller().ioremap_slice::<u8>(address, 4, MemoryType::WriteBack);
+      let matches = table_signature.map_or(false, |table_signature| &table_signature[..] == &signature[..]);
+      if matches {
+        return map_table(address);
+      }
+    }
+    None
+  }
+
+  pub fn madt(&self) -> Option<Madt> {
+    self.find_table(b"APIC").map(|table| Madt::parse(&table))
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicEntry {
+  pub processor_id: u32,
+  pub apic_id: u32
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+  pub id: u8,
+  pub address: PhysicalAddress,
+  // The first global system interrupt that this I/O APIC handles.
+  pub gsi_base: u32
+}
+
+// An ISA IRQ that isn't wired to the global system interrupt of the same number, or doesn't have
+// the ISA bus's usual active high, edge triggered signalling.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+  pub irq: u8,
+  pub gsi: u32,
+  pub active_low: bool,
+  pub level_triggered: bool
+}
+
+// The Multiple APIC Description Table, which lists the interrupt controllers of the machine.
+pub struct Madt {
+  pub local_apic_address: PhysicalAddress,
+  // Whether the machine also has the legacy 8259 PICs, which then need masking.
+  pub has_pics: bool,
+  pub local_apics: Vec<LocalApicEntry>,
+  pub io_apics: Vec<IoApicEntry>,
+  pub overrides: Vec<InterruptSourceOverride>
+}
+
+impl Madt {
+  fn parse(table: &[u8]) -> Madt {
+    let mut madt = Madt {
+      local_apic_address: read_u32(table, HEADER_SIZE) as PhysicalAddress,
+      has_pics: read_u32(table, HEADER_SIZE + 4) & 1 != 0,
+      local_apics: Vec::new(),
+      io_apics: Vec::new(),
+      overrides: Vec::new()
+    };
+    let mut offset = HEADER_SIZE + 8;
+    while offset + 2 <= table.len() {
+      let entry_type = read_u8(table, offset);
+      let length = read_u8(table, offset + 1) as usize;
+      if length < 2 || offset + length > table.len() {
+        println!("acpi: MADT entry at offset {} has a bad length", offset);
+        break;
+      }
+      let entry = &table[offset..offset + length];
+      match entry_type {
+        MADT_LOCAL_APIC if length >= 8 => {
+          if read_u32(entry, 4) & (PROCESSOR_ENABLED | PROCESSOR_ONLINE_CAPABLE) != 0 {
+            madt.local_apics.push(LocalApicEntry { processor_id: read_u8(entry, 2) as u32, apic_id: read_u8(entry, 3) as u32 });
+          }
+        },
+        MADT_IO_APIC if length >= 12 => {
+          madt.io_apics.push(IoApicEntry { id: read_u8(entry, 2), address: read_u32(entry, 4) as PhysicalAddress, gsi_base: read_u32(entry, 8) });
+        },
+        MADT_INTERRUPT_SOURCE_OVERRIDE if length >= 10 => {
+          // Polarity and trigger mode of 0 mean the bus's defaults, which for ISA are active high
+          // and edge triggered.
+          let flags = read_u16(entry, 8);
+          madt.overrides.push(InterruptSourceOverride {
+            irq: read_u8(entry, 3),
+            gsi: read_u32(entry, 4),
+            active_low: flags & 0b11 == 0b11,
+            level_triggered: (flags >> 2) & 0b11 == 0b11
+          });
+        },
+        MADT_LOCAL_APIC_ADDRESS_OVERRIDE if length >= 12 => {
+          madt.local_apic_address = read_u64(entry, 4);
+        },
+        MADT_LOCAL_X2APIC if length >= 16 => {
+          if read_u32(entry, 8) & (PROCESSOR_ENABLED | PROCESSOR_ONLINE_CAPABLE) != 0 {
+            madt.local_apics.push(LocalApicEntry { processor_id: read_u32(entry, 12), apic_id: read_u32(entry, 4) });
+          }
+        },
+        _ => {}
+      }
+      offset += length;
+    }
+    madt
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::*;
+
+  // A MADT with a local APIC address of 0xfee00000, the given flags and entries, whose header is
+  // left zeroed as parse doesn't look at it.
+  fn table(flags: u32, entries: &[&[u8]]) -> Vec<u8> {
+    let mut table = vec![0; HEADER_SIZE];
+    table.extend_from_slice(&[0x00, 0x00, 0xe0, 0xfe]);
+    table.extend_from_slice(&[flags as u8, 0, 0, 0]);
+    for entry in entries {
+      table.extend_from_slice(entry);
+    }
+    table
+  }
+
+  #[test]
+  fn parses_header() {
+    let madt = Madt::parse(&table(1, &[]));
+    assert_eq!(madt.local_apic_address, 0xfee0_0000);
+    assert!(madt.has_pics);
+    assert!(!Madt::parse(&table(0, &[])).has_pics);
+  }
+
+  #[test]
+  fn parses_local_apics() {
+    let madt = Madt::parse(&table(0, &[
+      &[MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0],
+      &[MADT_LOCAL_APIC, 8, 1, 2, 0, 0, 0, 0], // disabled
+      &[MADT_LOCAL_APIC, 8, 2, 4, 2, 0, 0, 0], // online capable
+      &[MADT_LOCAL_X2APIC, 16, 0, 0, 0x00, 0x01, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0]
+    ]));
+    let apics: Vec<(u32, u32)> = madt.local_apics.iter().map(|apic| (apic.processor_id, apic.apic_id)).collect();
+    assert_eq!(apics, [(0, 0), (2, 4), (3, 0x100)]);
+  }
+
+  #[test]
+  fn parses_io_apics_and_overrides() {
+    let madt = Madt::parse(&table(0, &[
+      &[MADT_IO_APIC, 12, 5, 0, 0x00, 0x00, 0xc0, 0xfe, 24, 0, 0, 0],
+      &[MADT_INTERRUPT_SOURCE_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0, 0],
+      &[MADT_INTERRUPT_SOURCE_OVERRIDE, 10, 0, 9, 9, 0, 0, 0, 0b1111, 0]
+    ]));
+    assert_eq!(madt.io_apics.len(), 1);
+    assert_eq!(madt.io_apics[0].id, 5);
+    assert_eq!(madt.io_apics[0].address, 0xfec0_0000);
+    assert_eq!(madt.io_apics[0].gsi_base, 24);
+    assert_eq!(madt.overrides.len(), 2);
+    assert_eq!((madt.overrides[0].irq, madt.overrides[0].gsi), (0, 2));
+    assert!(!madt.overrides[0].active_low && !madt.overrides[0].level_triggered);
+    assert_eq!((madt.overrides[1].irq, madt.overrides[1].gsi), (9, 9));
+    assert!(madt.overrides[1].active_low && madt.overrides[1].level_triggered);
+  }
+
+  #[test]
+  fn applies_local_apic_address_override() {
+    let madt = Madt::parse(&table(0, &[&[MADT_LOCAL_APIC_ADDRESS_OVERRIDE, 12, 0, 0, 0x00, 0x00, 0x00, 0x00, 0x01, 0, 0, 0]]));
+    assert_eq!(madt.local_apic_address, 0x1_0000_0000);
+  }
+
+  #[test]
+  fn stops_at_bad_entry_length() {
+    let madt = Madt::parse(&table(0, &[
+      &[MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0],
+      &[MADT_LOCAL_APIC, 0, 1, 1, 1, 0, 0, 0],
+      &[MADT_LOCAL_APIC, 8, 2, 2, 1, 0, 0, 0]
+    ]));
+    assert_eq!(madt.local_apics.len(), 1);
+    let madt = Madt::parse(&table(0, &[&[MADT_LOCAL_APIC, 12, 0, 0, 1, 0, 0, 0]]));
+    assert!(madt.local_apics.is_empty());
+  }
+}
@@ -1,11 +1,16 @@
 global start
 extern late_start
 
-section .text
+KERNEL_OFFSET equ 0xffffffff80000000
+
+; This code runs before paging is enabled, so it is linked at its physical address. Everything it
+; uses from other sections is linked in the higher half, so has KERNEL_OFFSET subtracted to get
+; the physical address.
+section .boot.text progbits alloc exec nowrite
 bits 32
 
 start:
-  mov esp, stack_top
+  mov esp, stack_top - KERNEL_OFFSET
   mov edi, ebx  ; Move Multiboot info pointer to edi
 
   ; Check basic requirements for startup
@@ -16,8 +21,8 @@ start:
   call set_up_page_tables
   call enter_long_mode_and_enable_paging
 
-  lgdt [gdt64.pointer]
-  jmp gdt64.code:late_start
+  lgdt [gdt64.pointer - KERNEL_OFFSET]
+  jmp gdt64.code:long_mode_start
 
 ; Check that the kernel was loaded by a Multiboot compliant bootloader
 check_multiboot:
@@ -65,33 +70,37 @@ check_long_mode:
   mov al, "2"
   jmp panic
 
-; Set up paging
+; Set up paging. The first 1 GiB of physical memory is mapped both at 0, so that this code keeps
+; running once paging is enabled, and at KERNEL_OFFSET, where the rest of the kernel is linked.
 set_up_page_tables:
-  mov eax, p4_table
+  ; P4 entry 510 maps the page tables themselves (entry 511 is needed for the kernel)
+  mov eax, p4_table - KERNEL_OFFSET
   or eax, 0b11
-  mov [p4_table + 511 * 8], eax
-  ; First P4 entry maps to P3
-  mov eax, p3_table
+  mov [p4_table - KERNEL_OFFSET + 510 * 8], eax
+  ; First and last P4 entries map to P3
+  mov eax, p3_table - KERNEL_OFFSET
   or eax, 0b11
-  mov [p4_table], eax
-  ; First P3 entry maps to P2
-  mov eax, p2_table
+  mov [p4_table - KERNEL_OFFSET], eax
+  mov [p4_table - KERNEL_OFFSET + 511 * 8], eax
+  ; First P3 entry and the one for KERNEL_OFFSET map to P2
+  mov eax, p2_table - KERNEL_OFFSET
   or eax, 0b11
-  mov [p3_table], eax
+  mov [p3_table - KERNEL_OFFSET], eax
+  mov [p3_table - KERNEL_OFFSET + 510 * 8], eax
   ; Now map all P2 entries to huge 2MiB pages
   mov ecx, 0
 .map_p2_table:
   mov eax, 0x200000
   mul ecx
   or eax, 0b10000011
-  mov [p2_table + ecx * 8], eax
+  mov [p2_table - KERNEL_OFFSET + ecx * 8], eax
   inc ecx
   cmp ecx, 512
   jne .map_p2_table
   ret
 
 enter_long_mode_and_enable_paging:
-  mov eax, p4_table
+  mov eax, p4_table - KERNEL_OFFSET
   mov cr3, eax
   mov eax, cr4
   or eax, 1 << 5
@@ -113,15 +122,25 @@ panic:
   mov byte [0xb800a], al
   hlt
 
+bits 64
+; Still running from the identity map, so switch the GDT and stack over to their higher half
+; addresses before jumping up to the rest of the kernel.
+long_mode_start:
+  lgdt [gdt64.high_pointer]
+  mov rsp, stack_top
+  mov rax, late_start
+  jmp rax
+
 section .bss
 align 4096
-; Page tables
-p4_table:
-  resb 4096
+; Page tables. The P4 goes right below the stack, as it becomes the stack's guard page once the
+; kernel switches to its own page table.
 p3_table:
   resb 4096
 p2_table:
   resb 4096
+p4_table:
+  resb 4096
 ; Stack
 stack_bottom:
   resb 4096 * 4
@@ -132,6 +151,10 @@ gdt64:
   dq 0
 .code: equ $ - gdt64
   dq (1 << 43) | (1 << 44) | (1 << 47) | (1 << 53)
+.limit: equ $ - gdt64 - 1
 .pointer:
-  dw $ - gdt64 - 1
+  dw .limit
+  dq gdt64 - KERNEL_OFFSET
+.high_pointer:
+  dw .limit
   dq gdt64
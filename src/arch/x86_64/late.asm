@@ -17,5 +17,5 @@ late_start:
 
   ; Print "OKAY" to the screen and halt.
   mov rax, 0x2f592f412f4b2f4f
-  mov qword [0xb8000], rax
+  mov qword [0xffffffff800b8000], rax
   hlt
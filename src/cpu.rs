@@ -0,0 +1,127 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count, CpuidResult};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const CR4_UMIP: u64 = 1 << 11;
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn cpuid(leaf: u32) -> CpuidResult {
+  // early.asm has already checked that CPUID and the extended leaves exist.
+  unsafe { __cpuid(leaf) }
+}
+
+// Leaf 7 has the structured extended feature flags, but older CPUs don't have it.
+fn extended_features() -> Option<CpuidResult> {
+  if cpuid(0).eax >= 7 {
+    Some(unsafe { __cpuid_count(7, 0) })
+  }
+  else {
+    None
+  }
+}
+
+pub fn has_1gib_pages() -> bool {
+  cpuid(0x8000_0001).edx & (1 << 26) != 0
+}
+
+pub fn has_apic() -> bool {
+  cpuid(1).edx & (1 << 9) != 0
+}
+
+pub fn has_x2apic() -> bool {
+  cpuid(1).ecx & (1 << 21) != 0
+}
+
+pub fn has_global_pages() -> bool {
+  cpuid(1).edx & (1 << 13) != 0
+}
+
+pub fn has_pcid() -> bool {
+  cpuid(1).ecx & (1 << 17) != 0
+}
+
+pub fn has_smep() -> bool {
+  extended_features().map_or(false, |features| features.ebx & (1 << 7) != 0)
+}
+
+pub fn has_smap() -> bool {
+  extended_features().map_or(false, |features| features.ebx & (1 << 20) != 0)
+}
+
+pub fn has_umip() -> bool {
+  extended_features().map_or(false, |features| features.ecx & (1 << 2) != 0)
+}
+
+// Stops the kernel from executing (SMEP) or touching (SMAP) user-accessible pages, and user code
+// from reading the descriptor table registers (UMIP), as far as the CPU supports them.
+pub fn enable_user_protection() {
+  let mut cr4 = read_cr4();
+  if has_smep() {
+    cr4 |= CR4_SMEP;
+  }
+  if has_smap() {
+    cr4 |= CR4_SMAP;
+  }
+  if has_umip() {
+    cr4 |= CR4_UMIP;
+  }
+  unsafe { write_cr4(cr4) };
+  SMAP_ENABLED.store(cr4 & CR4_SMAP != 0, Ordering::Relaxed);
+}
+
+// Lets the kernel access user-accessible pages for as long as this is alive, despite SMAP. This
+// should cover as little code as possible.
+pub struct UserAccess(());
+
+impl UserAccess {
+  pub fn begin() -> UserAccess {
+    // stac and clac are invalid opcodes on CPUs without SMAP.
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+      unsafe { asm!("stac" :::: "volatile") };
+    }
+    UserAccess(())
+  }
+}
+
+impl Drop for UserAccess {
+  fn drop(&mut self) {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+      unsafe { asm!("clac" :::: "volatile") };
+    }
+  }
+}
+
+// CR2 holds the address whose access caused the most recent page fault.
+pub fn read_cr2() -> u64 {
+  let value: u64;
+  unsafe { asm!("mov %cr2, $0" : "=r" (value)) };
+  value
+}
+
+// Unlike x86_64's Cr3, these keep the PCID in the low bits.
+pub fn read_cr3() -> u64 {
+  let value: u64;
+  unsafe { asm!("mov %cr3, $0" : "=r" (value)) };
+  value
+}
+
+pub unsafe fn write_cr3(value: u64) {
+  asm!("mov $0, %cr3" :: "r" (value) : "memory" : "volatile");
+}
+
+pub fn read_cr4() -> u64 {
+  let value: u64;
+  unsafe { asm!("mov %cr4, $0" : "=r" (value)) };
+  value
+}
+
+pub unsafe fn write_cr4(value: u64) {
+  asm!("mov $0, %cr4" :: "r" (value) : "memory" : "volatile");
+}
+
+// Writes back every modified cache line and invalidates the caches.
+pub fn write_back_caches() {
+  unsafe { asm!("wbinvd" :::: "memory" : "volatile") };
+}
@@ -0,0 +1,190 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::{Mutex, Once};
+use volatile::Volatile;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+use cpu;
+use memory::{self, IoMapping, MemoryType, PhysicalAddress};
+
+// The local APIC delivers this when an interrupt goes away before the CPU accepts it. Its low four
+// bits must be set on older CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+// In x2APIC mode, the register at offset n of the xAPIC page is this MSR plus n / 16.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// Register offsets in the xAPIC page.
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xb0;
+const SPURIOUS_INTERRUPT: usize = 0xf0;
+const INTERRUPT_COMMAND_LOW: usize = 0x300;
+const INTERRUPT_COMMAND_HIGH: usize = 0x310;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+
+// Fields of the interrupt command register.
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+const SHORTHAND_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+static LOCAL_APIC: Once<Mutex<LocalApic>> = Once::new();
+// The virtual address of the xAPIC's EOI register, or 0 in x2APIC mode. Every IRQ handler sends an
+// EOI, so this lets them do it without taking the LOCAL_APIC lock. Each CPU's local APIC is at
+// the same address, so one mapping serves them all.
+static XAPIC_EOI: AtomicUsize = AtomicUsize::new(0);
+
+enum Registers {
+  // The xAPIC's registers are in a page of device memory, 16 bytes apart.
+  XApic(IoMapping<[Volatile<u32>]>),
+  X2Apic
+}
+
+// The interrupt controller built into each CPU, which receives interrupts from the I/O APICs and
+// other CPUs.
+pub struct LocalApic {
+  registers: Registers
+}
+
+impl LocalApic {
+  // Enables the local APIC at physical, in x2APIC mode where the CPU supports it.
+  fn new(physical: PhysicalAddress) -> Option<LocalApic> {
+    let registers = if cpu::has_x2apic() {
+      Registers::X2Apic
+    }
+    else {
+      Registers::XApic(memory::controller().ioremap_slice(physical, 1024, MemoryType::Uncached)?)
+    };
+    let mut apic = LocalApic { registers };
+    apic.enable();
+    Some(apic)
+  }
+
+  // Enables the local APIC of the CPU this runs on.
+  fn enable(&mut self) {
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    unsafe {
+      apic_base.write(apic_base.read() | APIC_BASE_ENABLE);
+      // Going straight from disabled to x2APIC mode is an invalid transition, which faults.
+      if self.is_x2apic() {
+        apic_base.write(apic_base.read() | APIC_BASE_X2APIC_ENABLE);
+      }
+    }
+    self.write(TASK_PRIORITY, 0);
+    self.write(SPURIOUS_INTERRUPT, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+  }
+
+  fn read(&self, register: usize) -> u32 {
+    match self.registers {
+      Registers::XApic(ref registers) => registers[register / 4].read(),
+      Registers::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + register as u32 / 16).read() as u32 }
+    }
+  }
+
+  fn write(&mut self, register: usize, value: u32) {
+    match self.registers {
+      Registers::XApic(ref mut registers) => registers[register / 4].write(value),
+      Registers::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + register as u32 / 16).write(value as u64) }
+    }
+  }
+
+  pub fn is_x2apic(&self) -> bool {
+    match self.registers {
+      Registers::XApic(_) => false,
+      Registers::X2Apic => true
+    }
+  }
+
+  pub fn id(&self) -> u32 {
+    match self.registers {
+      Registers::XApic(_) => self.read(ID) >> 24,
+      Registers::X2Apic => self.read(ID)
+    }
+  }
+
+  // Sends an interrupt to the local APIC with the given ID. The x2APIC has a single 64-bit
+  // command register, while the xAPIC's is split in two and the low half sends it.
+  fn send_command(&mut self, destination: u32, command: u32) {
+    match self.registers {
+      Registers::XApic(ref mut registers) => {
+        registers[INTERRUPT_COMMAND_HIGH / 4].write(destination << 24);
+        registers[INTERRUPT_COMMAND_LOW / 4].write(command);
+        while registers[INTERRUPT_COMMAND_LOW / 4].read() & DELIVERY_PENDING != 0 {}
+      },
+      Registers::X2Apic => {
+        let register = X2APIC_MSR_BASE + INTERRUPT_COMMAND_LOW as u32 / 16;
+        unsafe { Msr::new(register).write((destination as u64) << 32 | command as u64) };
+      }
+    }
+  }
+
+  pub fn send_ipi(&mut self, destination: u32, vector: u8) {
+    self.send_command(destination, LEVEL_ASSERT | vector as u32);
+  }
+
+  pub fn send_ipi_to_others(&mut self, vector: u8) {
+    self.send_command(0, SHORTHAND_ALL_EXCLUDING_SELF | LEVEL_ASSERT | vector as u32);
+  }
+
+  // The INIT and startup IPIs used to start another CPU, which begins executing in real mode at
+  // the start of the given page below 1 MiB.
+  pub fn send_init(&mut self, destination: u32) {
+    self.send_command(destination, DELIVERY_INIT | LEVEL_ASSERT);
+  }
+
+  pub fn send_startup(&mut self, destination: u32, page: u8) {
+    self.send_command(destination, DELIVERY_STARTUP | LEVEL_ASSERT | page as u32);
+  }
+}
+
+// Enables this CPU's local APIC, returning false if the CPU doesn't have one.
+pub fn init(physical: PhysicalAddress) -> bool {
+  if !cpu::has_apic() {
+    return false;
+  }
+  match LocalApic::new(physical) {
+    Some(apic) => {
+      if let Registers::XApic(ref registers) = apic.registers {
+        XAPIC_EOI.store(&registers[EOI / 4] as *const Volatile<u32> as usize, Ordering::Relaxed);
+      }
+      LOCAL_APIC.call_once(|| Mutex::new(apic));
+      true
+    },
+    None => false
+  }
+}
+
+// Enables the local APIC of another CPU once it is running, in the same mode and with the same
+// settings as the boot CPU's. Must only be called after init has succeeded.
+pub fn init_ap() {
+  with(|apic| apic.enable());
+}
+
+pub fn is_enabled() -> bool {
+  LOCAL_APIC.try().is_some()
+}
+
+// Like the PICS lock, this is taken by IRQ handlers, so must only be held with interrupts disabled.
+pub fn with<F, R>(f: F) -> R where F: FnOnce(&mut LocalApic) -> R {
+  let apic = LOCAL_APIC.try().expect("the local APIC is not enabled");
+  interrupts::without_interrupts(|| f(&mut apic.lock()))
+}
+
+pub fn end_of_interrupt() {
+  debug_assert!(is_enabled(), "the local APIC is not enabled");
+  match XAPIC_EOI.load(Ordering::Relaxed) {
+    0 => unsafe { Msr::new(X2APIC_MSR_BASE + EOI as u32 / 16).write(0) },
+    eoi => unsafe { (*(eoi as *mut Volatile<u32>)).write(0) }
+  }
+}
+
+// Spurious interrupts must not be acknowledged.
+pub extern "x86-interrupt" fn spurious_handler(_stack_frame: &mut ExceptionStackFrame) {}
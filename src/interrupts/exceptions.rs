@@ -0,0 +1,306 @@
+use bit_field::BitField;
+
+use core::fmt;
+
+use x86_64::registers::control::{Cr0, Cr3};
+use x86_64::structures::idt::{ExceptionStackFrame, PageFaultErrorCode};
+
+use cpu;
+use memory;
+
+// Not yet part of PageFaultErrorCode: set if the access was blocked by a protection key.
+const PAGE_FAULT_PROTECTION_KEY: u64 = 1 << 5;
+
+// The general purpose registers of the interrupted code, in the order the entry stubs push them.
+#[repr(C)]
+pub struct Registers {
+  pub r15: u64, pub r14: u64, pub r13: u64, pub r12: u64, pub r11: u64, pub r10: u64, pub r9: u64,
+  pub r8: u64, pub rbp: u64, pub rdi: u64, pub rsi: u64, pub rdx: u64, pub rcx: u64, pub rbx: u64,
+  pub rax: u64
+}
+
+// What an entry stub hands to its handler. Exceptions that don't push an error code get a zero in
+// its place, so that every handler sees the same layout.
+#[repr(C)]
+pub struct ExceptionContext {
+  pub registers: Registers,
+  pub error_code: u64,
+  pub stack_frame: ExceptionStackFrame
+}
+
+pub type ExceptionStub = extern "C" fn();
+
+// Defines an entry stub that saves the registers, calls the handler with them, and restores them
+// and returns from the exception if the handler returns. x86-interrupt functions can't be used
+// here, as by the time their body runs the registers have already been saved somewhere unknown.
+macro_rules! exception_stub {
+  // The stack is 16 byte aligned before the CPU pushes the stack frame, so after the error code and
+  // 15 registers it is 8 bytes off.
+  (@body $handler:ident) => {
+    asm!("push rax
+          push rbx
+          push rcx
+          push rdx
+          push rsi
+          push rdi
+          push rbp
+          push r8
+          push r9
+          push r10
+          push r11
+          push r12
+          push r13
+          push r14
+          push r15
+          mov rdi, rsp
+          sub rsp, 8
+          cld
+          call $0
+          add rsp, 8
+          pop r15
+          pop r14
+          pop r13
+          pop r12
+          pop r11
+          pop r10
+          pop r9
+          pop r8
+          pop rbp
+          pop rdi
+          pop rsi
+          pop rdx
+          pop rcx
+          pop rbx
+          pop rax
+          add rsp, 8
+          iretq"
+         :: "i"($handler as extern "C" fn(&mut ExceptionContext)) :: "intel", "volatile");
+    ::core::intrinsics::unreachable();
+  };
+  ($name:ident, $handler:ident) => {
+    #[naked]
+    pub extern "C" fn $name() {
+      unsafe {
+        asm!("push 0" :::: "intel", "volatile");
+        exception_stub!(@body $handler);
+      }
+    }
+  };
+  ($name:ident, $handler:ident, error_code) => {
+    #[naked]
+    pub extern "C" fn $name() {
+      unsafe {
+        exception_stub!(@body $handler);
+      }
+    }
+  };
+}
+
+// Reports an exception that the kernel cannot recover from, then halts.
+fn oops(name: &str, vector: u8, context: &ExceptionContext, details: Option<fmt::Arguments>) -> ! {
+  println!("KERNEL OOPS: {} (vector {})", name, vector);
+  if let Some(details) = details {
+    println!("{}", details);
+  }
+  print_context(context);
+  println!("sleeping now...");
+  loop {}
+}
+
+fn print_context(context: &ExceptionContext) {
+  let stack_frame = &context.stack_frame;
+  let registers = &context.registers;
+  println!("rip: {:#018x}  cs: {:#06x}  rflags: {:#010x}", stack_frame.instruction_pointer.as_u64(), stack_frame.code_segment, stack_frame.cpu_flags);
+  println!("rsp: {:#018x}  ss: {:#06x}", stack_frame.stack_pointer.as_u64(), stack_frame.stack_segment);
+  println!("rax: {:#018x}  rbx: {:#018x}  rcx: {:#018x}", registers.rax, registers.rbx, registers.rcx);
+  println!("rdx: {:#018x}  rsi: {:#018x}  rdi: {:#018x}", registers.rdx, registers.rsi, registers.rdi);
+  println!("rbp: {:#018x}  r8:  {:#018x}  r9:  {:#018x}", registers.rbp, registers.r8, registers.r9);
+  println!("r10: {:#018x}  r11: {:#018x}  r12: {:#018x}", registers.r10, registers.r11, registers.r12);
+  println!("r13: {:#018x}  r14: {:#018x}  r15: {:#018x}", registers.r13, registers.r14, registers.r15);
+  println!("cr0: {:#010x}  cr2: {:#018x}  cr3: {:#018x}  cr4: {:#010x}", Cr0::read_raw(), cpu::read_cr2(), Cr3::read().0.start_address().as_u64(), cpu::read_cr4());
+}
+
+// The error code pushed by exceptions that can be caused by loading a segment selector.
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.0 == 0 {
+      return write!(f, "error code: 0 (not caused by a segment selector)");
+    }
+    let table = match self.0.get_bits(1..3) {
+      0b00 => "GDT",
+      0b10 => "LDT",
+      _ => "IDT"
+    };
+    write!(f, "error code: {:#x} (selector index {} in the {}{})", self.0, self.0.get_bits(3..16), table,
+           if self.0.get_bit(0) { ", caused by an external event" } else { "" })
+  }
+}
+
+struct PageFault {
+  address: u64,
+  error_code: PageFaultErrorCode
+}
+
+impl fmt::Display for PageFault {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let error_code = self.error_code;
+    writeln!(f, "address: {:#x}", self.address)?;
+    writeln!(f, "error code: {:#x} ({} on {} in {} mode{}{})",
+             error_code.bits(),
+             if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) { "protection violation" } else { "page not present" },
+             if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) { "instruction fetch" }
+             else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) { "write" }
+             else { "read" },
+             if error_code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" },
+             if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) { ", reserved bit set in page table" } else { "" },
+             if error_code.bits() & PAGE_FAULT_PROTECTION_KEY != 0 { ", protection key" } else { "" })?;
+    write!(f, "page table: {}", memory::walk_active_table(self.address))?;
+    if let Some(name) = memory::guard_page_owner(self.address) {
+      write!(f, "\nstack overflow on stack '{}'", name)?;
+    }
+    Ok(())
+  }
+}
+
+extern "C" fn divide_error(context: &mut ExceptionContext) {
+  oops("DIVIDE ERROR", 0, context, None);
+}
+
+// Debug exceptions, NMIs and breakpoints are reported, but execution carries on afterwards.
+extern "C" fn debug(context: &mut ExceptionContext) {
+  println!("EXCEPTION: DEBUG");
+  print_context(context);
+}
+
+extern "C" fn non_maskable_interrupt(context: &mut ExceptionContext) {
+  println!("EXCEPTION: NON-MASKABLE INTERRUPT");
+  print_context(context);
+}
+
+extern "C" fn breakpoint(context: &mut ExceptionContext) {
+  println!("EXCEPTION: BREAKPOINT");
+  print_context(context);
+}
+
+extern "C" fn overflow(context: &mut ExceptionContext) {
+  oops("OVERFLOW", 4, context, None);
+}
+
+extern "C" fn bound_range_exceeded(context: &mut ExceptionContext) {
+  oops("BOUND RANGE EXCEEDED", 5, context, None);
+}
+
+extern "C" fn invalid_opcode(context: &mut ExceptionContext) {
+  oops("INVALID OPCODE", 6, context, None);
+}
+
+extern "C" fn device_not_available(context: &mut ExceptionContext) {
+  oops("DEVICE NOT AVAILABLE", 7, context, None);
+}
+
+// A stack overflow ends up here, as the page fault on the guard page can't push its stack frame
+// there either. CR2 still holds the address of that fault.
+extern "C" fn double_fault(context: &mut ExceptionContext) {
+  let address = cpu::read_cr2();
+  match memory::guard_page_owner(address) {
+    Some(name) => oops("DOUBLE FAULT", 8, context, Some(format_args!("stack overflow on stack '{}' (address: {:#x})", name, address))),
+    None => oops("DOUBLE FAULT", 8, context, None)
+  }
+}
+
+extern "C" fn coprocessor_segment_overrun(context: &mut ExceptionContext) {
+  oops("COPROCESSOR SEGMENT OVERRUN", 9, context, None);
+}
+
+extern "C" fn invalid_tss(context: &mut ExceptionContext) {
+  oops("INVALID TSS", 10, context, Some(format_args!("{}", SelectorErrorCode(context.error_code))));
+}
+
+extern "C" fn segment_not_present(context: &mut ExceptionContext) {
+  oops("SEGMENT NOT PRESENT", 11, context, Some(format_args!("{}", SelectorErrorCode(context.error_code))));
+}
+
+extern "C" fn stack_segment_fault(context: &mut ExceptionContext) {
+  oops("STACK SEGMENT FAULT", 12, context, Some(format_args!("{}", SelectorErrorCode(context.error_code))));
+}
+
+extern "C" fn general_protection_fault(context: &mut ExceptionContext) {
+  oops("GENERAL PROTECTION FAULT", 13, context, Some(format_args!("{}", SelectorErrorCode(context.error_code))));
+}
+
+extern "C" fn page_fault(context: &mut ExceptionContext) {
+  let address = cpu::read_cr2();
+  let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+  let resolved = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+    error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && memory::handle_write_fault(address)
+  }
+  else {
+    memory::handle_page_fault(address)
+  };
+  if resolved {
+    return;
+  }
+  oops("PAGE FAULT", 14, context, Some(format_args!("{}", PageFault { address, error_code })));
+}
+
+extern "C" fn x87_floating_point(context: &mut ExceptionContext) {
+  oops("X87 FLOATING POINT EXCEPTION", 16, context, None);
+}
+
+extern "C" fn alignment_check(context: &mut ExceptionContext) {
+  oops("ALIGNMENT CHECK", 17, context, None);
+}
+
+extern "C" fn machine_check(context: &mut ExceptionContext) {
+  oops("MACHINE CHECK", 18, context, None);
+}
+
+extern "C" fn simd_floating_point(context: &mut ExceptionContext) {
+  oops("SIMD FLOATING POINT EXCEPTION", 19, context, None);
+}
+
+extern "C" fn virtualization(context: &mut ExceptionContext) {
+  oops("VIRTUALIZATION EXCEPTION", 20, context, None);
+}
+
+extern "C" fn control_protection(context: &mut ExceptionContext) {
+  let cause = match context.error_code.get_bits(0..15) {
+    1 => "near return",
+    2 => "far return or iret",
+    3 => "missing endbranch",
+    4 => "rstorssp",
+    5 => "setssbsy",
+    _ => "unknown"
+  };
+  oops("CONTROL PROTECTION EXCEPTION", 21, context, Some(format_args!("error code: {:#x} ({}{})", context.error_code, cause,
+                                                                   if context.error_code.get_bit(15) { ", in an enclave" } else { "" })));
+}
+
+extern "C" fn security_exception(context: &mut ExceptionContext) {
+  oops("SECURITY EXCEPTION", 30, context, Some(format_args!("error code: {:#x}", context.error_code)));
+}
+
+exception_stub!(divide_error_stub, divide_error);
+exception_stub!(debug_stub, debug);
+exception_stub!(non_maskable_interrupt_stub, non_maskable_interrupt);
+exception_stub!(breakpoint_stub, breakpoint);
+exception_stub!(overflow_stub, overflow);
+exception_stub!(bound_range_exceeded_stub, bound_range_exceeded);
+exception_stub!(invalid_opcode_stub, invalid_opcode);
+exception_stub!(device_not_available_stub, device_not_available);
+exception_stub!(double_fault_stub, double_fault, error_code);
+exception_stub!(coprocessor_segment_overrun_stub, coprocessor_segment_overrun);
+exception_stub!(invalid_tss_stub, invalid_tss, error_code);
+exception_stub!(segment_not_present_stub, segment_not_present, error_code);
+exception_stub!(stack_segment_fault_stub, stack_segment_fault, error_code);
+exception_stub!(general_protection_fault_stub, general_protection_fault, error_code);
+exception_stub!(page_fault_stub, page_fault, error_code);
+exception_stub!(x87_floating_point_stub, x87_floating_point);
+exception_stub!(alignment_check_stub, alignment_check, error_code);
+exception_stub!(machine_check_stub, machine_check);
+exception_stub!(simd_floating_point_stub, simd_floating_point);
+exception_stub!(virtualization_stub, virtualization);
+exception_stub!(control_protection_stub, control_protection, error_code);
+exception_stub!(security_exception_stub, security_exception, error_code);
@@ -0,0 +1,141 @@
+use alloc::vec::Vec;
+
+use spin::{Mutex, Once};
+use volatile::Volatile;
+use x86_64::instructions::interrupts;
+
+use acpi::{InterruptSourceOverride, IoApicEntry};
+use memory::{self, IoMapping, MemoryType};
+
+// The I/O APIC has an index register at offset 0 and a data window at offset 0x10, through which
+// all the others are accessed.
+const REGISTER_SELECT: usize = 0;
+const REGISTER_WINDOW: usize = 4;
+
+const VERSION: u32 = 0x01;
+// Each redirection entry takes two registers, starting here.
+const REDIRECTION_TABLE: u32 = 0x10;
+
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+
+static IO_APICS: Once<Mutex<IoApics>> = Once::new();
+
+// Routes the device interrupts it receives, numbered from gsi_base, to local APICs.
+pub struct IoApic {
+  registers: IoMapping<[Volatile<u32>]>,
+  gsi_base: u32,
+  entry_count: u32
+}
+
+impl IoApic {
+  fn new(entry: &IoApicEntry) -> Option<IoApic> {
+    let registers = memory::controller().ioremap_slice(entry.address, 5, MemoryType::Uncached)?;
+    let mut io_apic = IoApic { registers, gsi_base: entry.gsi_base, entry_count: 0 };
+    io_apic.entry_count = ((io_apic.read(VERSION) >> 16) & 0xff) + 1;
+    // Nothing should be delivered until a driver asks for it.
+    for gsi in entry.gsi_base..entry.gsi_base + io_apic.entry_count {
+      io_apic.set_entry(gsi, MASKED);
+    }
+    Some(io_apic)
+  }
+
+  fn read(&mut self, register: u32) -> u32 {
+    self.registers[REGISTER_SELECT].write(register);
+    self.registers[REGISTER_WINDOW].read()
+  }
+
+  fn write(&mut self, register: u32, value: u32) {
+    self.registers[REGISTER_SELECT].write(register);
+    self.registers[REGISTER_WINDOW].write(value);
+  }
+
+  fn handles(&self, gsi: u32) -> bool {
+    gsi >= self.gsi_base && gsi < self.gsi_base + self.entry_count
+  }
+
+  fn entry(&mut self, gsi: u32) -> u64 {
+    let register = REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+    self.read(register) as u64 | (self.read(register + 1) as u64) << 32
+  }
+
+  fn set_entry(&mut self, gsi: u32, entry: u64) {
+    let register = REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+    // The low half has the mask bit, so is written last to avoid delivering a half-written entry.
+    self.write(register + 1, (entry >> 32) as u32);
+    self.write(register, entry as u32);
+  }
+}
+
+// Every I/O APIC, and how ISA IRQs map onto their interrupts.
+struct IoApics {
+  io_apics: Vec<IoApic>,
+  overrides: Vec<InterruptSourceOverride>,
+  // The local APIC that interrupts are sent to.
+  destination: u32
+}
+
+impl IoApics {
+  fn find(&mut self, gsi: u32) -> &mut IoApic {
+    self.io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi))
+        .unwrap_or_else(|| panic!("no I/O APIC handles interrupt {}", gsi))
+  }
+
+  // ISA IRQs are identity mapped unless the MADT overrides them.
+  fn isa_override(&self, irq: u8) -> InterruptSourceOverride {
+    self.overrides.iter().find(|source| source.irq == irq).cloned()
+        .unwrap_or(InterruptSourceOverride { irq, gsi: irq as u32, active_low: false, level_triggered: false })
+  }
+}
+
+pub fn init(entries: &[IoApicEntry], overrides: &[InterruptSourceOverride], destination: u32) {
+  assert!(destination <= 0xff, "interrupts can't be routed to local APIC {} without interrupt remapping", destination);
+  let io_apics = entries.iter().filter_map(|entry| {
+    let io_apic = IoApic::new(entry);
+    if io_apic.is_none() {
+      println!("ioapic: failed to map the I/O APIC at {:#x}", entry.address);
+    }
+    io_apic
+  }).collect();
+  IO_APICS.call_once(|| Mutex::new(IoApics { io_apics, overrides: overrides.to_vec(), destination }));
+}
+
+fn with<F, R>(f: F) -> R where F: FnOnce(&mut IoApics) -> R {
+  let io_apics = IO_APICS.try().expect("the I/O APICs have not been set up");
+  interrupts::without_interrupts(|| f(&mut io_apics.lock()))
+}
+
+// Sends the ISA IRQ to the given vector, taking interrupt source overrides into account. The IRQ
+// stays masked until unmask_irq is called.
+pub fn route_irq(irq: u8, vector: u8) {
+  with(|io_apics| {
+    let source = io_apics.isa_override(irq);
+    let mut entry = MASKED | vector as u64 | (io_apics.destination as u64) << 56;
+    if source.active_low {
+      entry |= ACTIVE_LOW;
+    }
+    if source.level_triggered {
+      entry |= LEVEL_TRIGGERED;
+    }
+    io_apics.find(source.gsi).set_entry(source.gsi, entry);
+  });
+}
+
+pub fn mask_irq(irq: u8) {
+  with(|io_apics| {
+    let gsi = io_apics.isa_override(irq).gsi;
+    let io_apic = io_apics.find(gsi);
+    let entry = io_apic.entry(gsi);
+    io_apic.set_entry(gsi, entry | MASKED);
+  });
+}
+
+pub fn unmask_irq(irq: u8) {
+  with(|io_apics| {
+    let gsi = io_apics.isa_override(irq).gsi;
+    let io_apic = io_apics.find(gsi);
+    let entry = io_apic.entry(gsi);
+    io_apic.set_entry(gsi, entry & !MASKED);
+  });
+}
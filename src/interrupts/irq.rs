@@ -0,0 +1,202 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{ExceptionStackFrame, HandlerFunc};
+
+use super::{apic, pic};
+use super::pic::{PICS, PRIMARY_OFFSET, IRQ_COUNT};
+
+// Handlers can be registered for vectors FIRST_VECTOR to FIRST_VECTOR + VECTOR_COUNT - 1. The
+// first 16 are the ISA IRQs, and the rest are free for IPIs and the like.
+pub const FIRST_VECTOR: u8 = PRIMARY_OFFSET;
+pub const VECTOR_COUNT: usize = 32;
+
+// Whether a handler dealt with the interrupt. Devices sharing a line each check whether the
+// interrupt was theirs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqResult {
+  Handled,
+  NotMine
+}
+
+pub type HandlerId = usize;
+
+struct Handler {
+  id: HandlerId,
+  name: &'static str,
+  function: Box<FnMut() -> IrqResult + Send>
+}
+
+#[derive(Default)]
+struct Vector {
+  handlers: Vec<Handler>,
+  count: u64,
+  // Interrupts that none of the handlers claimed.
+  unhandled: u64
+}
+
+static NEXT_HANDLER_ID: AtomicUsize = AtomicUsize::new(1);
+
+lazy_static! {
+  // Handlers are called with this held, so they must not register or unregister handlers.
+  static ref VECTORS: Mutex<[Vector; VECTOR_COUNT]> = Mutex::new(Default::default());
+}
+
+// The IDT entries for every vector that handlers can be registered for, which all pass the
+// vector on to dispatch.
+macro_rules! stubs {
+  ($($vector:expr => $name:ident),*) => {
+    $(extern "x86-interrupt" fn $name(_stack_frame: &mut ExceptionStackFrame) {
+      dispatch($vector);
+    })*
+
+    pub const STUBS: [HandlerFunc; VECTOR_COUNT] = [$($name),*];
+  }
+}
+
+stubs!(0 => vector_32, 1 => vector_33, 2 => vector_34, 3 => vector_35, 4 => vector_36, 5 => vector_37,
+       6 => vector_38, 7 => vector_39, 8 => vector_40, 9 => vector_41, 10 => vector_42, 11 => vector_43,
+       12 => vector_44, 13 => vector_45, 14 => vector_46, 15 => vector_47, 16 => vector_48,
+       17 => vector_49, 18 => vector_50, 19 => vector_51, 20 => vector_52, 21 => vector_53,
+       22 => vector_54, 23 => vector_55, 24 => vector_56, 25 => vector_57, 26 => vector_58,
+       27 => vector_59, 28 => vector_60, 29 => vector_61, 30 => vector_62, 31 => vector_63);
+
+fn irq_of(index: usize) -> Option<u8> {
+  if index < IRQ_COUNT as usize { Some(index as u8) } else { None }
+}
+
+fn dispatch(index: usize) {
+  let irq = irq_of(index);
+  // The PICs can raise IRQ 7 or 15 and then withdraw it, which mustn't be handled or acknowledged.
+  if let Some(irq) = irq {
+    if (irq == 7 || irq == 15) && !apic::is_enabled() && PICS.lock().is_spurious(irq) {
+      return;
+    }
+  }
+
+  {
+    let mut vectors = VECTORS.lock();
+    let vector = &mut vectors[index];
+    vector.count += 1;
+    let mut handled = false;
+    for handler in vector.handlers.iter_mut() {
+      if (handler.function)() == IrqResult::Handled {
+        handled = true;
+      }
+    }
+    if !handled {
+      vector.unhandled += 1;
+    }
+  }
+
+  match irq {
+    Some(irq) => super::end_of_interrupt(irq),
+    None => if apic::is_enabled() { apic::end_of_interrupt() }
+  }
+}
+
+fn vector_index(vector: u8) -> usize {
+  assert!(vector >= FIRST_VECTOR && ((vector - FIRST_VECTOR) as usize) < VECTOR_COUNT,
+          "handlers can't be registered for vector {}", vector);
+  (vector - FIRST_VECTOR) as usize
+}
+
+// Adds a handler for the vector, which is called after any that are already registered. Returns
+// an ID to unregister it with. Needs the heap.
+pub fn register_vector<F>(vector: u8, name: &'static str, function: F) -> HandlerId where F: FnMut() -> IrqResult + Send + 'static {
+  let index = vector_index(vector);
+  let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+  let handler = Handler { id, name, function: Box::new(function) };
+  interrupts::without_interrupts(|| VECTORS.lock()[index].handlers.push(handler));
+  id
+}
+
+// Returns whether the handler was registered.
+pub fn unregister_vector(vector: u8, id: HandlerId) -> bool {
+  let index = vector_index(vector);
+  interrupts::without_interrupts(|| {
+    let handlers = &mut VECTORS.lock()[index].handlers;
+    let count = handlers.len();
+    handlers.retain(|handler| handler.id != id);
+    handlers.len() != count
+  })
+}
+
+fn has_handlers(index: usize) -> bool {
+  interrupts::without_interrupts(|| !VECTORS.lock()[index].handlers.is_empty())
+}
+
+// Like register_vector, but for an ISA IRQ, which is unmasked when its first handler is added.
+pub fn register_irq<F>(irq: u8, name: &'static str, function: F) -> HandlerId where F: FnMut() -> IrqResult + Send + 'static {
+  let vector = pic::vector(irq);
+  let first = !has_handlers(vector_index(vector));
+  let id = register_vector(vector, name, function);
+  if first {
+    super::unmask_irq(irq);
+  }
+  id
+}
+
+// The IRQ is masked again once its last handler is gone.
+pub fn unregister_irq(irq: u8, id: HandlerId) -> bool {
+  let vector = pic::vector(irq);
+  let unregistered = unregister_vector(vector, id);
+  if unregistered && !has_handlers(vector_index(vector)) {
+    super::mask_irq(irq);
+  }
+  unregistered
+}
+
+struct VectorStats {
+  vector: u8,
+  count: u64,
+  unhandled: u64,
+  handlers: Vec<&'static str>
+}
+
+// Counts of the interrupts received on each vector that has had any or has handlers, in the style
+// of /proc/interrupts.
+pub struct IrqStats {
+  vectors: Vec<VectorStats>,
+  spurious: usize
+}
+
+pub fn stats() -> IrqStats {
+  interrupts::without_interrupts(|| {
+    let vectors = VECTORS.lock();
+    IrqStats {
+      vectors: vectors.iter().enumerate()
+                      .filter(|&(_, vector)| vector.count > 0 || !vector.handlers.is_empty())
+                      .map(|(index, vector)| VectorStats {
+                        vector: FIRST_VECTOR + index as u8,
+                        count: vector.count,
+                        unhandled: vector.unhandled,
+                        handlers: vector.handlers.iter().map(|handler| handler.name).collect()
+                      })
+                      .collect(),
+      spurious: pic::spurious_irqs()
+    }
+  })
+}
+
+impl fmt::Display for IrqStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "vector  irq       count   unhandled  handlers")?;
+    for stats in self.vectors.iter() {
+      write!(f, "\n{:>6}  ", stats.vector)?;
+      match irq_of((stats.vector - FIRST_VECTOR) as usize) {
+        Some(irq) => write!(f, "{:>3}", irq)?,
+        None => write!(f, "  -")?
+      }
+      write!(f, "  {:>10}  {:>10}  ", stats.count, stats.unhandled)?;
+      for (i, name) in stats.handlers.iter().enumerate() {
+        write!(f, "{}{}", if i == 0 { "" } else { ", " }, name)?;
+      }
+    }
+    write!(f, "\nspurious: {}", self.spurious)
+  }
+}
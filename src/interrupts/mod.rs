@@ -1,41 +1,98 @@
+pub mod apic;
+mod exceptions;
 mod gdt;
+pub mod ioapic;
+mod irq;
+pub mod pic;
 
+use core::mem;
+
+use multiboot2::BootInformation;
 use spin::Once;
 use x86_64;
+use x86_64::instructions::interrupts;
 use x86_64::instructions::segmentation::set_cs;
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::SegmentSelector;
-use x86_64::structures::idt::{InterruptDescriptorTable, ExceptionStackFrame};
+use x86_64::structures::idt::{InterruptDescriptorTable, Entry, EntryOptions, HandlerFunc};
 use x86_64::structures::tss::TaskStateSegment;
 
-use memory::MemoryController;
+use acpi::{Acpi, Madt};
+use memory;
+use self::exceptions::*;
 use self::gdt::{Gdt, Descriptor};
+use self::pic::PICS;
+pub use self::irq::{register_irq, unregister_irq, register_vector, unregister_vector, stats, HandlerId, IrqResult};
 
 static TSS: Once<TaskStateSegment> = Once::new();
 static GDT: Once<Gdt> = Once::new();
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
 
+// Points the IDT entry for the vector at an exception entry stub. The x86_64 crate's IDT only takes
+// x86-interrupt functions, and has no public entry for vectors 9 and 21, so its entries are treated
+// as a plain array of 256 here, which is how they are laid out. Every Entry has the same layout
+// whatever its handler type, as it only stores the handler's address.
+fn set_stub(idt: &mut InterruptDescriptorTable, vector: usize, stub: ExceptionStub) -> &mut EntryOptions {
+  let entries = unsafe { &mut *(idt as *mut InterruptDescriptorTable as *mut [Entry<HandlerFunc>; 256]) };
+  let handler: HandlerFunc = unsafe { mem::transmute(stub) };
+  entries[vector].set_handler_fn(handler)
+}
+
+// Never called: transmute refuses to compile if the IDT is not the size of 256 entries.
+#[allow(dead_code)]
+fn assert_idt_layout(idt: InterruptDescriptorTable) -> [Entry<HandlerFunc>; 256] {
+  unsafe { mem::transmute(idt) }
+}
+
 lazy_static! {
   static ref IDT: InterruptDescriptorTable = {
     let mut idt = InterruptDescriptorTable::new();
-    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    set_stub(&mut idt, 0, divide_error_stub);
+    set_stub(&mut idt, 1, debug_stub);
+    set_stub(&mut idt, 2, non_maskable_interrupt_stub);
+    set_stub(&mut idt, 3, breakpoint_stub);
+    set_stub(&mut idt, 4, overflow_stub);
+    set_stub(&mut idt, 5, bound_range_exceeded_stub);
+    set_stub(&mut idt, 6, invalid_opcode_stub);
+    set_stub(&mut idt, 7, device_not_available_stub);
+    set_stub(&mut idt, 9, coprocessor_segment_overrun_stub);
+    set_stub(&mut idt, 10, invalid_tss_stub);
+    set_stub(&mut idt, 11, segment_not_present_stub);
+    set_stub(&mut idt, 12, stack_segment_fault_stub);
+    set_stub(&mut idt, 13, general_protection_fault_stub);
+    // Page faults stay on the current stack, as an IST stack would be reused by a nested fault.
+    // A fault on a stack's guard page can't push its stack frame, so becomes a double fault.
+    set_stub(&mut idt, 14, page_fault_stub);
+    set_stub(&mut idt, 16, x87_floating_point_stub);
+    set_stub(&mut idt, 17, alignment_check_stub);
+    set_stub(&mut idt, 18, machine_check_stub);
+    set_stub(&mut idt, 19, simd_floating_point_stub);
+    set_stub(&mut idt, 20, virtualization_stub);
+    set_stub(&mut idt, 21, control_protection_stub);
+    set_stub(&mut idt, 30, security_exception_stub);
+    for (i, &stub) in irq::STUBS.iter().enumerate() {
+      idt[irq::FIRST_VECTOR as usize + i].set_handler_fn(stub);
+    }
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic::spurious_handler);
     unsafe {
-      idt.double_fault.set_handler_fn(double_fault_handler)
-                      .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
+      set_stub(&mut idt, 8, double_fault_stub).set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
     }
     idt
   };
 }
 
-pub fn init(mem_controller: &mut MemoryController) {
+pub fn init() {
+  let mut mem_controller = memory::controller();
   // Allocate a clean stack for use when calling the double-fault exception handler.
-  let double_fault_stack = mem_controller.alloc_stack(1)
+  let double_fault_stack = mem_controller.alloc_stack(1, "double fault")
                                          .expect("failed to allocate double fault stack");
   let tss = TSS.call_once(|| {
     let mut tss = TaskStateSegment::new();
     tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = x86_64::VirtAddr::new(double_fault_stack.top());
     tss
   });
+  // This stack is used for as long as the kernel runs, so it must never be freed.
+  mem::forget(double_fault_stack);
 
   let mut code_selector = SegmentSelector(0);
   let mut tss_selector = SegmentSelector(0);
@@ -52,17 +109,62 @@ pub fn init(mem_controller: &mut MemoryController) {
     load_tss(tss_selector);
   }
 
+  // Until the PICs are remapped, IRQs would arrive on the vectors of CPU exceptions.
+  PICS.lock().init();
   IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
-  println!("EXCEPTION: BREAKPOINT");
-  println!("{:#?}", stack_frame);
+// Moves interrupt delivery from the PICs to the local and I/O APICs described by the MADT, if there
+// is one and the CPU has a local APIC. IRQs keep the vectors they had on the PICs. Needs the heap.
+pub fn init_apic(boot_info: &BootInformation) -> Option<Madt> {
+  let madt = Acpi::new(boot_info).and_then(|acpi| acpi.madt())?;
+  if !apic::init(madt.local_apic_address) {
+    return None;
+  }
+  // The PICs' inputs are also wired to the I/O APIC, so they must not deliver anything themselves.
+  if madt.has_pics {
+    interrupts::without_interrupts(|| PICS.lock().set_masks(0xffff));
+  }
+  let bsp = apic::with(|apic| apic.id());
+  ioapic::init(&madt.io_apics, &madt.overrides, bsp);
+  Some(madt)
+}
+
+// Acknowledges the IRQ with whichever controller delivered it, once its handlers have run.
+fn end_of_interrupt(irq: u8) {
+  if apic::is_enabled() {
+    apic::end_of_interrupt();
+  }
+  else {
+    PICS.lock().end_of_interrupt(irq);
+  }
+}
+
+// Starts or stops delivery of an ISA IRQ, which arrives on vector pic::vector(irq) either way.
+pub fn unmask_irq(irq: u8) {
+  if apic::is_enabled() {
+    ioapic::route_irq(irq, pic::vector(irq));
+    ioapic::unmask_irq(irq);
+  }
+  else {
+    pic::unmask(irq);
+  }
+}
+
+pub fn mask_irq(irq: u8) {
+  if apic::is_enabled() {
+    ioapic::mask_irq(irq);
+  }
+  else {
+    pic::mask(irq);
+  }
+}
+
+// Starts delivering hardware interrupts. Lines stay masked on the PICs until a driver unmasks them.
+pub fn enable() {
+  interrupts::enable();
 }
 
-extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
-  println!("EXCEPTION: DOUBLE FAULT");
-  println!("{:#?}", stack_frame);
-  println!("sleeping now...");
-  loop {}
+pub fn disable() {
+  interrupts::disable();
 }
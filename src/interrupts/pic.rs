@@ -0,0 +1,159 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+// The legacy IRQs are moved past the 32 vectors reserved for CPU exceptions.
+pub const PRIMARY_OFFSET: u8 = 32;
+pub const SECONDARY_OFFSET: u8 = PRIMARY_OFFSET + 8;
+pub const IRQ_COUNT: u8 = 16;
+
+// The secondary PIC's output is wired to this line of the primary.
+const CASCADE_IRQ: u8 = 2;
+
+const ICW1_ICW4: u8 = 0x01;
+const ICW1_INIT: u8 = 0x10;
+const ICW4_8086: u8 = 0x01;
+const OCW2_EOI: u8 = 0x20;
+const OCW3_READ_ISR: u8 = 0x0b;
+
+// Interrupts that the PICs raised on IRQ 7 or 15 but then withdrew, which must not be
+// acknowledged as if they were real.
+static SPURIOUS_IRQS: AtomicUsize = AtomicUsize::new(0);
+
+pub static PICS: Mutex<ChainedPics> = Mutex::new(ChainedPics::new());
+
+struct Pic {
+  offset: u8,
+  command: Port<u8>,
+  data: Port<u8>
+}
+
+impl Pic {
+  const fn new(offset: u8, command: u16, data: u16) -> Pic {
+    Pic { offset, command: Port::new(command), data: Port::new(data) }
+  }
+
+  fn in_service(&mut self) -> u8 {
+    unsafe {
+      self.command.write(OCW3_READ_ISR);
+      self.command.read()
+    }
+  }
+
+  fn end_of_interrupt(&mut self) {
+    unsafe { self.command.write(OCW2_EOI) };
+  }
+}
+
+// The primary and secondary 8259 PICs of a PC, which deliver IRQs 0-7 and 8-15 respectively.
+pub struct ChainedPics {
+  primary: Pic,
+  secondary: Pic
+}
+
+impl ChainedPics {
+  const fn new() -> ChainedPics {
+    ChainedPics {
+      primary: Pic::new(PRIMARY_OFFSET, 0x20, 0x21),
+      secondary: Pic::new(SECONDARY_OFFSET, 0xa0, 0xa1)
+    }
+  }
+
+  // Reinitialises both PICs with their new vector offsets, leaving every line masked apart from
+  // the cascade, so that nothing is delivered until a driver unmasks its IRQ.
+  pub fn init(&mut self) {
+    // The PICs can be slow to respond, so each write is followed by one to an unused port.
+    let mut wait_port: Port<u8> = Port::new(0x80);
+    let mut wait = || unsafe { wait_port.write(0) };
+    unsafe {
+      self.primary.command.write(ICW1_INIT | ICW1_ICW4);
+      wait();
+      self.secondary.command.write(ICW1_INIT | ICW1_ICW4);
+      wait();
+      self.primary.data.write(self.primary.offset);
+      wait();
+      self.secondary.data.write(self.secondary.offset);
+      wait();
+      self.primary.data.write(1 << CASCADE_IRQ);
+      wait();
+      self.secondary.data.write(CASCADE_IRQ);
+      wait();
+      self.primary.data.write(ICW4_8086);
+      wait();
+      self.secondary.data.write(ICW4_8086);
+      wait();
+    }
+    self.set_masks(!(1 << CASCADE_IRQ));
+  }
+
+  // The mask of all 16 lines, with IRQ 0 in bit 0. A set bit means the line is masked.
+  pub fn masks(&self) -> u16 {
+    unsafe { self.primary.data.read() as u16 | (self.secondary.data.read() as u16) << 8 }
+  }
+
+  pub fn set_masks(&mut self, masks: u16) {
+    unsafe {
+      self.primary.data.write(masks as u8);
+      self.secondary.data.write((masks >> 8) as u8);
+    }
+  }
+
+  pub fn mask(&mut self, irq: u8) {
+    assert!(irq < IRQ_COUNT, "no such IRQ {}", irq);
+    let masks = self.masks();
+    self.set_masks(masks | 1 << irq);
+  }
+
+  pub fn unmask(&mut self, irq: u8) {
+    assert!(irq < IRQ_COUNT, "no such IRQ {}", irq);
+    let masks = self.masks();
+    self.set_masks(masks & !(1 << irq));
+  }
+
+  // Must be called at the end of every IRQ handler that isn't for a spurious IRQ. IRQs from the
+  // secondary PIC need acknowledging on both.
+  pub fn end_of_interrupt(&mut self, irq: u8) {
+    if irq >= 8 {
+      self.secondary.end_of_interrupt();
+    }
+    self.primary.end_of_interrupt();
+  }
+
+  // Checks whether an interrupt on IRQ 7 or 15 was spurious, in which case it must not be
+  // acknowledged on the PIC that raised it. For a spurious IRQ 15, the primary PIC still saw a
+  // real interrupt on the cascade line, so that is acknowledged here.
+  pub fn is_spurious(&mut self, irq: u8) -> bool {
+    let spurious = match irq {
+      7 => self.primary.in_service() & (1 << 7) == 0,
+      15 => self.secondary.in_service() & (1 << 7) == 0,
+      _ => false
+    };
+    if spurious {
+      if irq == 15 {
+        self.primary.end_of_interrupt();
+      }
+      SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+    }
+    spurious
+  }
+}
+
+// The PICS lock is also taken by IRQ handlers, so it must only be held with interrupts disabled.
+pub fn mask(irq: u8) {
+  interrupts::without_interrupts(|| PICS.lock().mask(irq));
+}
+
+pub fn unmask(irq: u8) {
+  interrupts::without_interrupts(|| PICS.lock().unmask(irq));
+}
+
+pub fn vector(irq: u8) -> u8 {
+  assert!(irq < IRQ_COUNT, "no such IRQ {}", irq);
+  PRIMARY_OFFSET + irq
+}
+
+pub fn spurious_irqs() -> usize {
+  SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,154 @@
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+// Reads give the status register, and writes send commands to the controller.
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xa7;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST_PORT: u8 = 0xab;
+const DISABLE_FIRST_PORT: u8 = 0xad;
+const ENABLE_FIRST_PORT: u8 = 0xae;
+
+const CONFIG_FIRST_PORT_INTERRUPT: u8 = 1 << 0;
+const CONFIG_SECOND_PORT_INTERRUPT: u8 = 1 << 1;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Commands for the keyboard itself, and its replies.
+const KEYBOARD_RESET: u8 = 0xff;
+const KEYBOARD_ACK: u8 = 0xfa;
+const KEYBOARD_RESEND: u8 = 0xfe;
+const KEYBOARD_SELF_TEST_PASSED: u8 = 0xaa;
+
+// How many times to poll the status register before giving up on the controller.
+const TIMEOUT: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControllerError {
+  // The controller or keyboard didn't respond in time, which is what happens if there isn't one.
+  Timeout,
+  SelfTestFailed(u8),
+  PortTestFailed(u8),
+  // The keyboard replied to a command with this instead of acknowledging it.
+  NotAcknowledged(u8)
+}
+
+// The PS/2 controller, which the keyboard is connected to through its first port.
+pub struct Controller {
+  data: Port<u8>,
+  command: Port<u8>
+}
+
+impl Controller {
+  pub const fn new() -> Controller {
+    Controller { data: Port::new(DATA_PORT), command: Port::new(COMMAND_PORT) }
+  }
+
+  fn status(&self) -> u8 {
+    unsafe { self.command.read() }
+  }
+
+  fn wait_for(&self, status_bit: u8, set: bool) -> Result<(), ControllerError> {
+    for _ in 0..TIMEOUT {
+      if (self.status() & status_bit != 0) == set {
+        return Ok(());
+      }
+    }
+    Err(ControllerError::Timeout)
+  }
+
+  pub fn read(&mut self) -> Result<u8, ControllerError> {
+    self.wait_for(STATUS_OUTPUT_FULL, true)?;
+    Ok(unsafe { self.data.read() })
+  }
+
+  // Reads the data port without waiting, for the keyboard IRQ handler, which is only called once
+  // there is a byte.
+  pub fn read_data(&mut self) -> u8 {
+    unsafe { self.data.read() }
+  }
+
+  pub fn write(&mut self, value: u8) -> Result<(), ControllerError> {
+    self.wait_for(STATUS_INPUT_FULL, false)?;
+    unsafe { self.data.write(value) };
+    Ok(())
+  }
+
+  fn send_command(&mut self, command: u8) -> Result<(), ControllerError> {
+    self.wait_for(STATUS_INPUT_FULL, false)?;
+    unsafe { self.command.write(command) };
+    Ok(())
+  }
+
+  fn read_config(&mut self) -> Result<u8, ControllerError> {
+    self.send_command(READ_CONFIG)?;
+    self.read()
+  }
+
+  fn write_config(&mut self, config: u8) -> Result<(), ControllerError> {
+    self.send_command(WRITE_CONFIG)?;
+    self.write(config)
+  }
+
+  // Sends a command to the keyboard, resending it if the keyboard asks.
+  fn send_to_keyboard(&mut self, command: u8) -> Result<(), ControllerError> {
+    for _ in 0..3 {
+      self.write(command)?;
+      match self.read()? {
+        KEYBOARD_ACK => return Ok(()),
+        KEYBOARD_RESEND => continue,
+        reply => return Err(ControllerError::NotAcknowledged(reply))
+      }
+    }
+    Err(ControllerError::NotAcknowledged(KEYBOARD_RESEND))
+  }
+
+  // Tests the controller and resets the keyboard, leaving its interrupt disabled. Returns whether
+  // the controller translates scancodes to set 1.
+  pub fn init(&mut self) -> Result<bool, ControllerError> {
+    self.send_command(DISABLE_FIRST_PORT)?;
+    self.send_command(DISABLE_SECOND_PORT)?;
+    // Throw away anything the firmware left behind.
+    while self.status() & STATUS_OUTPUT_FULL != 0 {
+      self.read_data();
+    }
+
+    let config = self.read_config()? & !(CONFIG_FIRST_PORT_INTERRUPT | CONFIG_SECOND_PORT_INTERRUPT);
+    self.write_config(config)?;
+
+    self.send_command(SELF_TEST)?;
+    match self.read()? {
+      SELF_TEST_PASSED => {},
+      result => return Err(ControllerError::SelfTestFailed(result))
+    }
+    // The self test resets the configuration on some controllers.
+    self.write_config(config)?;
+
+    self.send_command(TEST_FIRST_PORT)?;
+    match self.read()? {
+      PORT_TEST_PASSED => {},
+      result => return Err(ControllerError::PortTestFailed(result))
+    }
+
+    self.send_command(ENABLE_FIRST_PORT)?;
+    self.send_to_keyboard(KEYBOARD_RESET)?;
+    match self.read()? {
+      KEYBOARD_SELF_TEST_PASSED => {},
+      result => return Err(ControllerError::SelfTestFailed(result))
+    }
+    Ok(config & CONFIG_TRANSLATION != 0)
+  }
+
+  pub fn enable_interrupt(&mut self) -> Result<(), ControllerError> {
+    let config = self.read_config()?;
+    self.write_config(config | CONFIG_FIRST_PORT_INTERRUPT)
+  }
+}
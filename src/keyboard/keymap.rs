@@ -0,0 +1,236 @@
+use super::scancode::KeyCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+  Us,
+  Uk,
+  De
+}
+
+// Which modifiers are held, or for the locks, toggled on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Modifiers {
+  pub left_shift: bool,
+  pub right_shift: bool,
+  pub left_control: bool,
+  pub right_control: bool,
+  pub alt: bool,
+  // The right alt key, which picks a third character on European layouts.
+  pub alt_gr: bool,
+  pub caps_lock: bool,
+  pub num_lock: bool
+}
+
+impl Modifiers {
+  pub fn shift(&self) -> bool {
+    self.left_shift || self.right_shift
+  }
+
+  pub fn control(&self) -> bool {
+    self.left_control || self.right_control
+  }
+}
+
+// The characters on a key: without modifiers, with shift, and with AltGr. Caps lock only affects
+// letters.
+struct Key {
+  normal: char,
+  shifted: char,
+  alt_gr: Option<char>,
+  letter: bool
+}
+
+fn letter(normal: char, shifted: char) -> Key {
+  Key { normal, shifted, alt_gr: None, letter: true }
+}
+
+fn symbol(normal: char, shifted: char) -> Key {
+  Key { normal, shifted, alt_gr: None, letter: false }
+}
+
+fn with_alt_gr(mut key: Key, alt_gr: char) -> Key {
+  key.alt_gr = Some(alt_gr);
+  key
+}
+
+impl Layout {
+  pub fn from_name(name: &str) -> Option<Layout> {
+    match name {
+      "us" => Some(Layout::Us),
+      "uk" | "gb" => Some(Layout::Uk),
+      "de" => Some(Layout::De),
+      _ => None
+    }
+  }
+
+  // Picks the layout named by a keymap= option, such as keymap=de.
+  pub fn from_command_line(command_line: &str) -> Option<Layout> {
+    command_line.split(' ')
+                .filter(|option| option.starts_with("keymap="))
+                .filter_map(|option| Layout::from_name(&option["keymap=".len()..]))
+                .next()
+  }
+
+  // The character that pressing the key gives, if any. Keys that don't depend on the layout, such
+  // as Enter and the keypad, are handled by map.
+  fn key(&self, code: KeyCode) -> Option<Key> {
+    use self::KeyCode::*;
+    let key = match (*self, code) {
+      (Layout::Uk, Backquote) => with_alt_gr(symbol('`', '¬'), '¦'),
+      (Layout::Uk, Key2) => symbol('2', '"'),
+      (Layout::Uk, Key3) => symbol('3', '£'),
+      (Layout::Uk, Key4) => with_alt_gr(symbol('4', '$'), '€'),
+      (Layout::Uk, Quote) => symbol('\'', '@'),
+      (Layout::Uk, Hash) => symbol('#', '~'),
+      (Layout::Uk, IsoBackslash) => symbol('\\', '|'),
+
+      (Layout::De, Backquote) => symbol('^', '°'),
+      (Layout::De, Key2) => with_alt_gr(symbol('2', '"'), '²'),
+      (Layout::De, Key3) => with_alt_gr(symbol('3', '§'), '³'),
+      (Layout::De, Key6) => symbol('6', '&'),
+      (Layout::De, Key7) => with_alt_gr(symbol('7', '/'), '{'),
+      (Layout::De, Key8) => with_alt_gr(symbol('8', '('), '['),
+      (Layout::De, Key9) => with_alt_gr(symbol('9', ')'), ']'),
+      (Layout::De, Key0) => with_alt_gr(symbol('0', '='), '}'),
+      (Layout::De, Minus) => with_alt_gr(symbol('ß', '?'), '\\'),
+      (Layout::De, Equals) => symbol('´', '`'),
+      (Layout::De, Q) => with_alt_gr(letter('q', 'Q'), '@'),
+      (Layout::De, E) => with_alt_gr(letter('e', 'E'), '€'),
+      (Layout::De, Y) => letter('z', 'Z'),
+      (Layout::De, Z) => letter('y', 'Y'),
+      (Layout::De, M) => with_alt_gr(letter('m', 'M'), 'µ'),
+      (Layout::De, LeftBracket) => letter('ü', 'Ü'),
+      (Layout::De, RightBracket) => with_alt_gr(symbol('+', '*'), '~'),
+      (Layout::De, Semicolon) => letter('ö', 'Ö'),
+      (Layout::De, Quote) => letter('ä', 'Ä'),
+      (Layout::De, Hash) => symbol('#', '\''),
+      (Layout::De, IsoBackslash) => with_alt_gr(symbol('<', '>'), '|'),
+      (Layout::De, Comma) => symbol(',', ';'),
+      (Layout::De, Period) => symbol('.', ':'),
+      (Layout::De, Slash) => symbol('-', '_'),
+
+      (_, Backquote) => symbol('`', '~'),
+      (_, Key1) => symbol('1', '!'),
+      (_, Key2) => symbol('2', '@'),
+      (_, Key3) => symbol('3', '#'),
+      (_, Key4) => symbol('4', '$'),
+      (_, Key5) => symbol('5', '%'),
+      (_, Key6) => symbol('6', '^'),
+      (_, Key7) => symbol('7', '&'),
+      (_, Key8) => symbol('8', '*'),
+      (_, Key9) => symbol('9', '('),
+      (_, Key0) => symbol('0', ')'),
+      (_, Minus) => symbol('-', '_'),
+      (_, Equals) => symbol('=', '+'),
+      (_, LeftBracket) => symbol('[', '{'),
+      (_, RightBracket) => symbol(']', '}'),
+      (_, Semicolon) => symbol(';', ':'),
+      (_, Quote) => symbol('\'', '"'),
+      (_, Hash) => symbol('\\', '|'),
+      (_, IsoBackslash) => symbol('\\', '|'),
+      (_, Comma) => symbol(',', '<'),
+      (_, Period) => symbol('.', '>'),
+      (_, Slash) => symbol('/', '?'),
+      (_, Q) => letter('q', 'Q'), (_, W) => letter('w', 'W'), (_, E) => letter('e', 'E'),
+      (_, R) => letter('r', 'R'), (_, T) => letter('t', 'T'), (_, Y) => letter('y', 'Y'),
+      (_, U) => letter('u', 'U'), (_, I) => letter('i', 'I'), (_, O) => letter('o', 'O'),
+      (_, P) => letter('p', 'P'), (_, A) => letter('a', 'A'), (_, S) => letter('s', 'S'),
+      (_, D) => letter('d', 'D'), (_, F) => letter('f', 'F'), (_, G) => letter('g', 'G'),
+      (_, H) => letter('h', 'H'), (_, J) => letter('j', 'J'), (_, K) => letter('k', 'K'),
+      (_, L) => letter('l', 'L'), (_, Z) => letter('z', 'Z'), (_, X) => letter('x', 'X'),
+      (_, C) => letter('c', 'C'), (_, V) => letter('v', 'V'), (_, B) => letter('b', 'B'),
+      (_, N) => letter('n', 'N'), (_, M) => letter('m', 'M'),
+      _ => return None
+    };
+    Some(key)
+  }
+
+  // Translates a key press into the character it types, if it types one.
+  pub fn map(&self, code: KeyCode, modifiers: &Modifiers) -> Option<char> {
+    use self::KeyCode::*;
+    match code {
+      Enter | KeypadEnter => return Some('\n'),
+      Tab => return Some('\t'),
+      Backspace => return Some('\x08'),
+      Space => return Some(' '),
+      Escape => return Some('\x1b'),
+      KeypadSlash => return Some('/'),
+      KeypadStar => return Some('*'),
+      KeypadMinus => return Some('-'),
+      KeypadPlus => return Some('+'),
+      _ => {}
+    }
+    if modifiers.num_lock {
+      let digit = match code {
+        Keypad0 => Some('0'), Keypad1 => Some('1'), Keypad2 => Some('2'), Keypad3 => Some('3'),
+        Keypad4 => Some('4'), Keypad5 => Some('5'), Keypad6 => Some('6'), Keypad7 => Some('7'),
+        Keypad8 => Some('8'), Keypad9 => Some('9'), KeypadPeriod => Some(if *self == Layout::De { ',' } else { '.' }),
+        _ => None
+      };
+      if digit.is_some() {
+        return digit;
+      }
+    }
+
+    let key = self.key(code)?;
+    if modifiers.alt_gr {
+      return key.alt_gr;
+    }
+    // Control with a letter gives the ASCII control character, so Ctrl-C is 0x03.
+    if modifiers.control() {
+      return if key.letter && key.normal.is_ascii() { Some((key.normal as u8 & 0x1f) as char) } else { None };
+    }
+    let shifted = if key.letter { modifiers.shift() != modifiers.caps_lock } else { modifiers.shift() };
+    Some(if shifted { key.shifted } else { key.normal })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::KeyCode::*;
+
+  #[test]
+  fn applies_shift_and_caps_lock() {
+    let shift = Modifiers { left_shift: true, ..Modifiers::default() };
+    let caps_lock = Modifiers { caps_lock: true, ..Modifiers::default() };
+    let both = Modifiers { right_shift: true, caps_lock: true, ..Modifiers::default() };
+    assert_eq!(Layout::Us.map(A, &Modifiers::default()), Some('a'));
+    assert_eq!(Layout::Us.map(A, &shift), Some('A'));
+    assert_eq!(Layout::Us.map(A, &caps_lock), Some('A'));
+    assert_eq!(Layout::Us.map(A, &both), Some('a'));
+    assert_eq!(Layout::Us.map(Key1, &caps_lock), Some('1'));
+    assert_eq!(Layout::De.map(Minus, &caps_lock), Some('ß'));
+  }
+
+  #[test]
+  fn maps_control_letters() {
+    let control = Modifiers { right_control: true, ..Modifiers::default() };
+    assert_eq!(Layout::Us.map(C, &control), Some('\x03'));
+    assert_eq!(Layout::Us.map(Key1, &control), None);
+  }
+
+  #[test]
+  fn maps_keypad_with_num_lock() {
+    let num_lock = Modifiers { num_lock: true, ..Modifiers::default() };
+    assert_eq!(Layout::Us.map(Keypad7, &num_lock), Some('7'));
+    assert_eq!(Layout::Us.map(Keypad7, &Modifiers::default()), None);
+    assert_eq!(Layout::De.map(KeypadPeriod, &num_lock), Some(','));
+  }
+
+  #[test]
+  fn maps_layout_specific_keys() {
+    let alt_gr = Modifiers { alt_gr: true, ..Modifiers::default() };
+    assert_eq!(Layout::De.map(Z, &Modifiers::default()), Some('y'));
+    assert_eq!(Layout::De.map(Q, &alt_gr), Some('@'));
+    assert_eq!(Layout::Uk.map(Key3, &Modifiers { left_shift: true, ..Modifiers::default() }), Some('£'));
+    assert_eq!(Layout::Us.map(Q, &alt_gr), None);
+  }
+
+  #[test]
+  fn reads_layout_from_command_line() {
+    assert_eq!(Layout::from_command_line("quiet keymap=gb"), Some(Layout::Uk));
+    assert_eq!(Layout::from_command_line("keymap=xx"), None);
+    assert_eq!(Layout::from_command_line(""), None);
+  }
+}
@@ -0,0 +1,125 @@
+mod i8042;
+mod keymap;
+mod scancode;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use interrupts::{register_irq, IrqResult};
+use self::i8042::Controller;
+pub use self::i8042::ControllerError;
+pub use self::keymap::Layout;
+use self::keymap::Modifiers;
+pub use self::scancode::ScancodeSet;
+use self::scancode::{Decoder, KeyCode, KeyEvent, KeyState};
+
+const KEYBOARD_IRQ: u8 = 1;
+const INPUT_QUEUE_SIZE: usize = 256;
+
+// Characters typed but not yet read. This is filled by the IRQ handler, so it can't use the heap.
+struct InputQueue {
+  chars: [char; INPUT_QUEUE_SIZE],
+  start: usize,
+  len: usize
+}
+
+impl InputQueue {
+  const fn new() -> InputQueue {
+    InputQueue { chars: ['\0'; INPUT_QUEUE_SIZE], start: 0, len: 0 }
+  }
+
+  // Characters typed while the queue is full are dropped.
+  fn push(&mut self, c: char) {
+    if self.len < INPUT_QUEUE_SIZE {
+      self.chars[(self.start + self.len) % INPUT_QUEUE_SIZE] = c;
+      self.len += 1;
+    }
+  }
+
+  fn pop(&mut self) -> Option<char> {
+    if self.len == 0 {
+      return None;
+    }
+    let c = self.chars[self.start];
+    self.start = (self.start + 1) % INPUT_QUEUE_SIZE;
+    self.len -= 1;
+    Some(c)
+  }
+}
+
+struct Keyboard {
+  controller: Controller,
+  decoder: Decoder,
+  layout: Layout,
+  modifiers: Modifiers
+}
+
+impl Keyboard {
+  fn handle_event(&mut self, event: KeyEvent) -> Option<char> {
+    let down = event.state == KeyState::Down;
+    let modifiers = &mut self.modifiers;
+    match event.code {
+      KeyCode::LeftShift => modifiers.left_shift = down,
+      KeyCode::RightShift => modifiers.right_shift = down,
+      KeyCode::LeftControl => modifiers.left_control = down,
+      KeyCode::RightControl => modifiers.right_control = down,
+      KeyCode::LeftAlt => modifiers.alt = down,
+      KeyCode::RightAlt => modifiers.alt_gr = down,
+      KeyCode::CapsLock if down => modifiers.caps_lock = !modifiers.caps_lock,
+      KeyCode::NumLock if down => modifiers.num_lock = !modifiers.num_lock,
+      code if down => return self.layout.map(code, modifiers),
+      _ => {}
+    }
+    None
+  }
+}
+
+// Both are used by the IRQ handler, so must only be locked with interrupts disabled.
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
+  controller: Controller::new(),
+  decoder: Decoder::new(ScancodeSet::Set1),
+  layout: Layout::Us,
+  modifiers: Modifiers {
+    left_shift: false, right_shift: false, left_control: false, right_control: false,
+    alt: false, alt_gr: false, caps_lock: false, num_lock: false
+  }
+});
+static INPUT: Mutex<InputQueue> = Mutex::new(InputQueue::new());
+
+fn handle_irq() -> IrqResult {
+  let mut keyboard = KEYBOARD.lock();
+  let byte = keyboard.controller.read_data();
+  let c = match keyboard.decoder.add_byte(byte) {
+    Some(event) => keyboard.handle_event(event),
+    None => None
+  };
+  if let Some(c) = c {
+    INPUT.lock().push(c);
+  }
+  IrqResult::Handled
+}
+
+// Resets the keyboard and starts taking input from it, returning the scancode set it sends. Needs
+// the heap, and interrupts set up.
+pub fn init(layout: Layout) -> Result<ScancodeSet, ControllerError> {
+  let set = interrupts::without_interrupts(|| {
+    let mut keyboard = KEYBOARD.lock();
+    // Keyboards send set 2, which the controller usually translates to set 1.
+    let set = if keyboard.controller.init()? { ScancodeSet::Set1 } else { ScancodeSet::Set2 };
+    keyboard.decoder = Decoder::new(set);
+    keyboard.layout = layout;
+    Ok(set)
+  })?;
+  register_irq(KEYBOARD_IRQ, "keyboard", handle_irq);
+  interrupts::without_interrupts(|| KEYBOARD.lock().controller.enable_interrupt())?;
+  Ok(set)
+}
+
+pub fn set_layout(layout: Layout) {
+  interrupts::without_interrupts(|| KEYBOARD.lock().layout = layout);
+}
+
+// Takes the next typed character from the input queue, if there is one.
+pub fn read_char() -> Option<char> {
+  interrupts::without_interrupts(|| INPUT.lock().pop())
+}
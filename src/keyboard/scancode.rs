@@ -0,0 +1,233 @@
+// Keys by their position on the keyboard, named after what they are on a US layout. Hash and
+// IsoBackslash are the extra keys on ISO keyboards, next to Enter and left shift respectively; on
+// ANSI keyboards the key above Enter sends the same scancode as Hash, so it is called that here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+  Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+  Backquote, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0, Minus, Equals, Backspace,
+  Tab, Q, W, E, R, T, Y, U, I, O, P, LeftBracket, RightBracket, Enter,
+  CapsLock, A, S, D, F, G, H, J, K, L, Semicolon, Quote, Hash,
+  LeftShift, IsoBackslash, Z, X, C, V, B, N, M, Comma, Period, Slash, RightShift,
+  LeftControl, LeftGui, LeftAlt, Space, RightAlt, RightGui, Menu, RightControl,
+  PrintScreen, ScrollLock, Pause,
+  Insert, Home, PageUp, Delete, End, PageDown, Up, Left, Down, Right,
+  NumLock, KeypadSlash, KeypadStar, KeypadMinus, KeypadPlus, KeypadEnter, KeypadPeriod,
+  Keypad0, Keypad1, Keypad2, Keypad3, Keypad4, Keypad5, Keypad6, Keypad7, Keypad8, Keypad9
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+  Down,
+  Up
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+  pub code: KeyCode,
+  pub state: KeyState
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+  // What the i8042 controller hands on when it translates, and what the original PC keyboard sent.
+  Set1,
+  // What every keyboard sends by default.
+  Set2
+}
+
+const EXTENDED: u8 = 0xe0;
+// Starts the sequence for Pause, which has no break code.
+const PAUSE: u8 = 0xe1;
+const SET2_BREAK: u8 = 0xf0;
+const SET1_BREAK: u8 = 0x80;
+// Sent before and after some extended keys to undo the effect of shift or num lock, as if the
+// keyboard had pressed or released a shift key. These are dropped.
+const FAKE_SHIFT_SET1: u8 = 0x2a;
+const FAKE_SHIFT_SET2: u8 = 0x12;
+
+// Turns a stream of scancode bytes into key events.
+pub struct Decoder {
+  set: ScancodeSet,
+  extended: bool,
+  releasing: bool,
+  // The rest of the Pause sequence, which is skipped once its first byte has been seen.
+  pause_bytes: u8
+}
+
+impl Decoder {
+  pub const fn new(set: ScancodeSet) -> Decoder {
+    Decoder { set, extended: false, releasing: false, pause_bytes: 0 }
+  }
+
+  pub fn add_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+    if self.pause_bytes > 0 {
+      self.pause_bytes -= 1;
+      return None;
+    }
+    match byte {
+      EXTENDED => {
+        self.extended = true;
+        return None;
+      },
+      PAUSE => {
+        self.pause_bytes = match self.set { ScancodeSet::Set1 => 5, ScancodeSet::Set2 => 7 };
+        return Some(KeyEvent { code: KeyCode::Pause, state: KeyState::Down });
+      },
+      SET2_BREAK if self.set == ScancodeSet::Set2 => {
+        self.releasing = true;
+        return None;
+      },
+      _ => {}
+    }
+
+    let extended = self.extended;
+    let (code, state) = match self.set {
+      ScancodeSet::Set1 => {
+        let state = if byte & SET1_BREAK != 0 { KeyState::Up } else { KeyState::Down };
+        let byte = byte & !SET1_BREAK;
+        let code = if extended {
+          if byte == FAKE_SHIFT_SET1 { None } else { set1_extended(byte) }
+        }
+        else {
+          set1(byte)
+        };
+        (code, state)
+      },
+      ScancodeSet::Set2 => {
+        let state = if self.releasing { KeyState::Up } else { KeyState::Down };
+        let code = if extended {
+          if byte == FAKE_SHIFT_SET2 { None } else { set2_extended(byte) }
+        }
+        else {
+          set2(byte)
+        };
+        (code, state)
+      }
+    };
+    self.extended = false;
+    self.releasing = false;
+    code.map(|code| KeyEvent { code, state })
+  }
+}
+
+fn set1(byte: u8) -> Option<KeyCode> {
+  use self::KeyCode::*;
+  Some(match byte {
+    0x01 => Escape, 0x02 => Key1, 0x03 => Key2, 0x04 => Key3, 0x05 => Key4, 0x06 => Key5,
+    0x07 => Key6, 0x08 => Key7, 0x09 => Key8, 0x0a => Key9, 0x0b => Key0, 0x0c => Minus,
+    0x0d => Equals, 0x0e => Backspace, 0x0f => Tab, 0x10 => Q, 0x11 => W, 0x12 => E, 0x13 => R,
+    0x14 => T, 0x15 => Y, 0x16 => U, 0x17 => I, 0x18 => O, 0x19 => P, 0x1a => LeftBracket,
+    0x1b => RightBracket, 0x1c => Enter, 0x1d => LeftControl, 0x1e => A, 0x1f => S, 0x20 => D,
+    0x21 => F, 0x22 => G, 0x23 => H, 0x24 => J, 0x25 => K, 0x26 => L, 0x27 => Semicolon,
+    0x28 => Quote, 0x29 => Backquote, 0x2a => LeftShift, 0x2b => Hash, 0x2c => Z, 0x2d => X,
+    0x2e => C, 0x2f => V, 0x30 => B, 0x31 => N, 0x32 => M, 0x33 => Comma, 0x34 => Period,
+    0x35 => Slash, 0x36 => RightShift, 0x37 => KeypadStar, 0x38 => LeftAlt, 0x39 => Space,
+    0x3a => CapsLock, 0x3b => F1, 0x3c => F2, 0x3d => F3, 0x3e => F4, 0x3f => F5, 0x40 => F6,
+    0x41 => F7, 0x42 => F8, 0x43 => F9, 0x44 => F10, 0x45 => NumLock, 0x46 => ScrollLock,
+    0x47 => Keypad7, 0x48 => Keypad8, 0x49 => Keypad9, 0x4a => KeypadMinus, 0x4b => Keypad4,
+    0x4c => Keypad5, 0x4d => Keypad6, 0x4e => KeypadPlus, 0x4f => Keypad1, 0x50 => Keypad2,
+    0x51 => Keypad3, 0x52 => Keypad0, 0x53 => KeypadPeriod, 0x56 => IsoBackslash, 0x57 => F11,
+    0x58 => F12,
+    _ => return None
+  })
+}
+
+fn set1_extended(byte: u8) -> Option<KeyCode> {
+  use self::KeyCode::*;
+  Some(match byte {
+    0x1c => KeypadEnter, 0x1d => RightControl, 0x35 => KeypadSlash, 0x37 => PrintScreen,
+    0x38 => RightAlt, 0x47 => Home, 0x48 => Up, 0x49 => PageUp, 0x4b => Left, 0x4d => Right,
+    0x4f => End, 0x50 => Down, 0x51 => PageDown, 0x52 => Insert, 0x53 => Delete, 0x5b => LeftGui,
+    0x5c => RightGui, 0x5d => Menu,
+    _ => return None
+  })
+}
+
+fn set2(byte: u8) -> Option<KeyCode> {
+  use self::KeyCode::*;
+  Some(match byte {
+    0x01 => F9, 0x03 => F5, 0x04 => F3, 0x05 => F1, 0x06 => F2, 0x07 => F12, 0x09 => F10,
+    0x0a => F8, 0x0b => F6, 0x0c => F4, 0x0d => Tab, 0x0e => Backquote, 0x11 => LeftAlt,
+    0x12 => LeftShift, 0x14 => LeftControl, 0x15 => Q, 0x16 => Key1, 0x1a => Z, 0x1b => S,
+    0x1c => A, 0x1d => W, 0x1e => Key2, 0x21 => C, 0x22 => X, 0x23 => D, 0x24 => E, 0x25 => Key4,
+    0x26 => Key3, 0x29 => Space, 0x2a => V, 0x2b => F, 0x2c => T, 0x2d => R, 0x2e => Key5,
+    0x31 => N, 0x32 => B, 0x33 => H, 0x34 => G, 0x35 => Y, 0x36 => Key6, 0x3a => M, 0x3b => J,
+    0x3c => U, 0x3d => Key7, 0x3e => Key8, 0x41 => Comma, 0x42 => K, 0x43 => I, 0x44 => O,
+    0x45 => Key0, 0x46 => Key9, 0x49 => Period, 0x4a => Slash, 0x4b => L, 0x4c => Semicolon,
+    0x4d => P, 0x4e => Minus, 0x52 => Quote, 0x54 => LeftBracket, 0x55 => Equals,
+    0x58 => CapsLock, 0x59 => RightShift, 0x5a => Enter, 0x5b => RightBracket, 0x5d => Hash,
+    0x61 => IsoBackslash, 0x66 => Backspace, 0x69 => Keypad1, 0x6b => Keypad4, 0x6c => Keypad7,
+    0x70 => Keypad0, 0x71 => KeypadPeriod, 0x72 => Keypad2, 0x73 => Keypad5, 0x74 => Keypad6,
+    0x75 => Keypad8, 0x76 => Escape, 0x77 => NumLock, 0x78 => F11, 0x79 => KeypadPlus,
+    0x7a => Keypad3, 0x7b => KeypadMinus, 0x7c => KeypadStar, 0x7d => Keypad9, 0x7e => ScrollLock,
+    0x83 => F7,
+    _ => return None
+  })
+}
+
+fn set2_extended(byte: u8) -> Option<KeyCode> {
+  use self::KeyCode::*;
+  Some(match byte {
+    0x11 => RightAlt, 0x14 => RightControl, 0x1f => LeftGui, 0x27 => RightGui, 0x2f => Menu,
+    0x4a => KeypadSlash, 0x5a => KeypadEnter, 0x69 => End, 0x6b => Left, 0x6c => Home,
+    0x70 => Insert, 0x71 => Delete, 0x72 => Down, 0x74 => Right, 0x75 => Up, 0x7a => PageDown,
+    0x7c => PrintScreen, 0x7d => PageUp,
+    _ => return None
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+  use super::*;
+  use super::KeyCode::*;
+  use super::KeyState::*;
+
+  fn decode(set: ScancodeSet, bytes: &[u8]) -> Vec<(KeyCode, KeyState)> {
+    let mut decoder = Decoder::new(set);
+    bytes.iter().filter_map(|&byte| decoder.add_byte(byte)).map(|event| (event.code, event.state)).collect()
+  }
+
+  #[test]
+  fn decodes_set1() {
+    assert_eq!(decode(ScancodeSet::Set1, &[0x1e, 0x9e, 0x2a, 0x10, 0x90, 0xaa]),
+               [(A, Down), (A, Up), (LeftShift, Down), (Q, Down), (Q, Up), (LeftShift, Up)]);
+  }
+
+  #[test]
+  fn decodes_set2() {
+    assert_eq!(decode(ScancodeSet::Set2, &[0x1c, 0xf0, 0x1c, 0x12, 0x15, 0xf0, 0x15, 0xf0, 0x12]),
+               [(A, Down), (A, Up), (LeftShift, Down), (Q, Down), (Q, Up), (LeftShift, Up)]);
+  }
+
+  #[test]
+  fn decodes_extended_keys() {
+    assert_eq!(decode(ScancodeSet::Set1, &[0xe0, 0x48, 0xe0, 0xc8, 0xe0, 0x1d, 0x1d]),
+               [(Up, Down), (Up, Up), (RightControl, Down), (LeftControl, Down)]);
+    assert_eq!(decode(ScancodeSet::Set2, &[0xe0, 0x75, 0xe0, 0xf0, 0x75, 0xe0, 0x14, 0x14]),
+               [(Up, Down), (Up, Up), (RightControl, Down), (LeftControl, Down)]);
+  }
+
+  #[test]
+  fn drops_fake_shifts() {
+    // Print Screen as sent with num lock on, and Insert as sent with shift held.
+    assert_eq!(decode(ScancodeSet::Set1, &[0xe0, 0x2a, 0xe0, 0x37, 0xe0, 0xb7, 0xe0, 0xaa]),
+               [(PrintScreen, Down), (PrintScreen, Up)]);
+    assert_eq!(decode(ScancodeSet::Set2, &[0xe0, 0xf0, 0x12, 0xe0, 0x70, 0xe0, 0xf0, 0x70, 0xe0, 0x12]),
+               [(Insert, Down), (Insert, Up)]);
+  }
+
+  #[test]
+  fn decodes_pause() {
+    assert_eq!(decode(ScancodeSet::Set1, &[0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5, 0x1e]),
+               [(Pause, Down), (A, Down)]);
+    assert_eq!(decode(ScancodeSet::Set2, &[0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77, 0x1c]),
+               [(Pause, Down), (A, Down)]);
+  }
+
+  #[test]
+  fn ignores_unknown_scancodes() {
+    assert_eq!(decode(ScancodeSet::Set1, &[0x7f, 0x1e]), [(A, Down)]);
+    assert_eq!(decode(ScancodeSet::Set2, &[0x7f, 0xf0, 0x7f, 0x1c]), [(A, Down)]);
+  }
+}
@@ -1,13 +1,16 @@
 #![feature(abi_x86_interrupt)]
 #![feature(alloc)]
 #![feature(allocator_api)]
+#![feature(asm)]
 #![feature(const_fn)]
+#![feature(core_intrinsics)]
 #![feature(lang_items)]
+#![feature(naked_functions)]
 #![feature(panic_implementation)]
 #![feature(alloc_error_handler)]
 #![feature(ptr_internals)]
 #![feature(unique)]
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 #[macro_use] extern crate alloc;
 extern crate bit_field;
@@ -22,19 +25,50 @@ extern crate x86_64;
 
 #[macro_use] mod vga; // this is first so that other modules can use the macros
 
+mod acpi;
+mod cpu;
 mod interrupts;
+mod keyboard;
 mod memory;
 
 use core::panic::PanicInfo;
-use linked_list_allocator::LockedHeap;
+#[cfg(feature = "heap_debug")]
+use memory::debug_allocator::DebugAllocator;
+#[cfg(feature = "bump_allocator")]
+use memory::heap_allocator::BumpAllocator;
+#[cfg(not(feature = "bump_allocator"))]
+use memory::heap_allocator::GrowableHeap;
+#[cfg(not(feature = "bump_allocator"))]
+use memory::slab_allocator::SlabAllocator;
 use x86_64::registers::control::{Cr0, Cr0Flags};
 use x86_64::registers::model_specific::{Efer, EferFlags};
 
-pub const HEAP_START: u64 = 0o_000_001_000_000_0000;
+// The kernel is linked in the top 2 GiB of the address space, leaving the lower half free for user
+// space. Kernel data structures that are allocated at runtime live between the two.
+pub const KERNEL_OFFSET: u64 = 0xffff_ffff_8000_0000;
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xffff_8000_0000_0000;
+pub const HEAP_START: u64 = 0o_177777_600_000_000_000_0000;
 pub const HEAP_SIZE: u64 = 100 * 1024; // 100 KiB
+pub const HEAP_MAX_SIZE: u64 = 64 * 1024 * 1024; // 64 MiB
+pub const MMIO_START: u64 = 0o_177777_700_000_000_000_0000;
+pub const MMIO_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
 
-#[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+#[cfg(not(any(feature = "heap_debug", feature = "bump_allocator")))]
+#[cfg_attr(not(test), global_allocator)]
+static HEAP_ALLOCATOR: SlabAllocator = SlabAllocator::new(GrowableHeap::empty(HEAP_MAX_SIZE as usize));
+
+#[cfg(feature = "heap_debug")]
+#[cfg_attr(not(test), global_allocator)]
+static HEAP_ALLOCATOR: DebugAllocator = DebugAllocator::new(SlabAllocator::new(GrowableHeap::empty(HEAP_MAX_SIZE as usize)));
+
+// Never frees anything but the latest allocation, and doesn't grow past HEAP_SIZE, but is simple
+// enough to rule the heap out when chasing memory corruption.
+#[cfg(feature = "bump_allocator")]
+#[cfg_attr(not(test), global_allocator)]
+static HEAP_ALLOCATOR: BumpAllocator = BumpAllocator::new(HEAP_START as usize, (HEAP_START + HEAP_SIZE) as usize);
+
+#[cfg(all(feature = "heap_debug", feature = "bump_allocator"))]
+compile_error!("heap_debug only works with the slab allocator, not bump_allocator");
 
 #[no_mangle]
 pub extern fn rust_main(multiboot_info_addr: usize) {
@@ -43,7 +77,9 @@ pub extern fn rust_main(multiboot_info_addr: usize) {
   println!("");
 
   print!("Loading Multiboot tags... ");
-  let boot_info = unsafe { multiboot2::load(multiboot_info_addr) };
+  // The boot code only maps the first 1 GiB of physical memory into the higher half.
+  assert!(multiboot_info_addr < 0x4000_0000, "multiboot information is above 1 GiB");
+  let boot_info = unsafe { multiboot2::load(multiboot_info_addr + KERNEL_OFFSET as usize) };
   println!("done.");
 
   print!("Enabling NX... ");
@@ -54,15 +90,27 @@ pub extern fn rust_main(multiboot_info_addr: usize) {
   enable_write_protect();
   println!("done.");
 
-  let mut mem_controller = memory::init(&boot_info);
+  print!("Enabling SMEP, SMAP and UMIP... ");
+  cpu::enable_user_protection();
+  println!("done (SMEP: {}, SMAP: {}, UMIP: {}).", cpu::has_smep(), cpu::has_smap(), cpu::has_umip());
+
+  print!("Enabling global pages and PCIDs... ");
+  memory::init_tlb();
+  println!("done (global pages: {}, PCIDs: {}).", memory::global_pages_enabled(), memory::pcid_enabled());
+
+  print!("Programming the PAT... ");
+  memory::init_pat();
+  println!("done.");
+
+  memory::init(&boot_info);
 
   print!("Setting up interrupt handlers... ");
-  interrupts::init(&mut mem_controller);
+  interrupts::init();
   println!("done.");
 
   print!("Initialising the heap... ");
   unsafe {
-    HEAP_ALLOCATOR.lock().init(HEAP_START as usize, (HEAP_START + HEAP_SIZE) as usize);
+    HEAP_ALLOCATOR.init(HEAP_START as usize, HEAP_SIZE as usize);
   }
   println!("done.");
 
@@ -71,12 +119,62 @@ pub extern fn rust_main(multiboot_info_addr: usize) {
   let heap_test = Box::new(42);
   println!("success!");
 
+  print!("Setting up the APIC... ");
+  match interrupts::init_apic(&boot_info) {
+    Some(madt) => println!("done ({}, {} CPUs, {} I/O APICs).", if cpu::has_x2apic() { "x2APIC" } else { "xAPIC" },
+                           madt.local_apics.len(), madt.io_apics.len()),
+    None => println!("not available, using the PIC.")
+  }
+
+  print!("Enabling interrupts... ");
+  interrupts::enable();
+  println!("done.");
+
+  print!("Initialising the keyboard... ");
+  let layout = boot_info.command_line_tag()
+                        .and_then(|tag| keyboard::Layout::from_command_line(tag.command_line()))
+                        .unwrap_or(keyboard::Layout::Us);
+  match keyboard::init(layout) {
+    Ok(set) => println!("done ({:?} layout, scancode {:?}).", layout, set),
+    Err(error) => println!("failed: {:?}.", error)
+  }
+
+  print!("Verifying the page table... ");
+  let verified = memory::controller().verify_page_table();
+  println!("{}", if verified { "done." } else { "failed!" });
+
   println!("Testing breakpoint exception handling...");
   x86_64::instructions::int3();
 
   println!("");
-  println!("up and running. going to sleep now.");
-  loop {}
+  print_meminfo();
+
+  println!("");
+  print_interrupts();
+
+  println!("");
+  println!("up and running. type away!");
+  loop {
+    // A key that arrives between checking the queue and halting would otherwise go unseen until
+    // the next interrupt. sti only takes effect after the instruction following it, so nothing
+    // can be delivered before the hlt.
+    interrupts::disable();
+    match keyboard::read_char() {
+      Some(c) => {
+        interrupts::enable();
+        print!("{}", c);
+      },
+      None => unsafe { asm!("sti; hlt" :::: "volatile") }
+    }
+  }
+}
+
+fn print_meminfo() {
+  println!("{}", memory::controller().stats());
+}
+
+fn print_interrupts() {
+  println!("{}", interrupts::stats());
 }
 
 fn enable_nx() {
@@ -90,14 +188,19 @@ fn enable_write_protect() {
   unsafe { Cr0::write(Cr0::read() | Cr0Flags::WRITE_PROTECT) };
 }
 
+// Unit tests run on the host under std, which provides these itself.
+#[cfg(not(test))]
 #[lang = "eh_personality"] extern fn eh_personality() {}
 
+#[cfg(not(test))]
 #[panic_implementation]
 #[no_mangle]
-pub fn panic(_info: &PanicInfo) -> ! {
+pub fn panic(info: &PanicInfo) -> ! {
+  println!("PANIC: {}", info);
   loop {}
 }
 
+#[cfg(not(test))]
 #[alloc_error_handler]
 #[no_mangle]
 pub fn alloc_error(_: core::alloc::Layout) -> ! {
@@ -1,74 +1,201 @@
-use memory::{PhysicalPage, Allocator};
-use multiboot2::{MemoryAreaIter, MemoryArea};
+use memory::{PhysicalPage, Allocator, PAGE_SIZE};
+use multiboot2::MemoryAreaIter;
+
+// Physical memory above this address is never handed out, as the bitmap has no room to track it.
+pub const MAX_PHYSICAL_MEMORY: u64 = 4 * 1024 * 1024 * 1024; // 4 GiB
+const MAX_PAGES: usize = (MAX_PHYSICAL_MEMORY / PAGE_SIZE) as usize;
+const BITMAP_WORDS: usize = MAX_PAGES / 64;
+
+// One bit per physical page, set if the page is free. This lives in .bss rather than in the
+// allocator itself because it is far too large to move around on the boot stack.
+static mut FREE_BITMAP: [u64; BITMAP_WORDS] = [0; BITMAP_WORDS];
 
 pub struct AreaAllocator {
-  next_free: PhysicalPage,
-  current_area: Option<&'static MemoryArea>,
+  free: &'static mut [u64; BITMAP_WORDS],
+  free_count: usize,
+  // The number of pages that were free to begin with.
+  managed_pages: usize,
+  next_search: usize,
   areas: MemoryAreaIter,
-  kernel_start: PhysicalPage,
-  kernel_end: PhysicalPage,
-  multiboot_start: PhysicalPage,
-  multiboot_end: PhysicalPage
+  // The pages holding the kernel image and the multiboot information, which are never free.
+  kernel: (PhysicalPage, PhysicalPage),
+  multiboot: (PhysicalPage, PhysicalPage)
 }
 
 impl Allocator for AreaAllocator {
   fn allocate(&mut self) -> Option<PhysicalPage> {
-    if let Some(area) = self.current_area {
-      let page = PhysicalPage { number: self.next_free.number };
-      let current_area_last_page = {
-        let address = area.start_address() + area.size() - 1;
-        PhysicalPage::containing_address(address)
-      };
-      if page > current_area_last_page {
-        self.choose_next_area();
-      }
-      else if page >= self.kernel_start && page <= self.kernel_end {
-        self.next_free = PhysicalPage { number: self.kernel_end.number + 1 };
-      }
-      else if page >= self.multiboot_start && page <= self.multiboot_end {
-        self.next_free = PhysicalPage { number: self.multiboot_end.number + 1 };
-      }
-      else {
-        self.next_free.number += 1;
-        return Some(page);
-      }
-      self.allocate()
+    if self.free_count == 0 {
+      return None; // Nothing left
     }
-    else {
-      None // Nothing left
+    // Everything below next_search is known to be allocated, but we still wrap around rather
+    // than trusting the hint completely.
+    for i in (self.next_search..BITMAP_WORDS).chain(0..self.next_search) {
+      let word = self.free[i];
+      if word != 0 {
+        let bit = word.trailing_zeros() as usize;
+        self.free[i] &= !(1 << bit);
+        self.free_count -= 1;
+        self.next_search = i;
+        return Some(PhysicalPage { number: i * 64 + bit });
+      }
     }
+    panic!("free page count is {} but the bitmap is empty", self.free_count);
   }
 
   fn deallocate(&mut self, page: PhysicalPage) {
-    println!("warning: deallocation of physical pages is unimplemented");
+    assert!(self.is_usable(&page), "attempted to free physical page {:#x} which is not usable memory", page.start_address());
+    assert!(!self.is_reserved(&page), "attempted to free physical page {:#x} which belongs to the kernel or multiboot information", page.start_address());
+    let (word, bit) = (page.number / 64, page.number % 64);
+    assert!(self.free[word] & (1 << bit) == 0, "double free of physical page {:#x}", page.start_address());
+    self.free[word] |= 1 << bit;
+    self.free_count += 1;
+    if word < self.next_search {
+      self.next_search = word;
+    }
   }
 }
 
 impl AreaAllocator {
+  // Must only be called once, as every AreaAllocator shares the same bitmap.
   pub fn new(kernel_start: u64, kernel_end: u64, multiboot_start: u64, multiboot_end: u64, memory_areas: MemoryAreaIter) -> AreaAllocator {
+    AreaAllocator::with_bitmap(unsafe { &mut FREE_BITMAP }, kernel_start, kernel_end, multiboot_start, multiboot_end, memory_areas)
+  }
+
+  fn with_bitmap(bitmap: &'static mut [u64; BITMAP_WORDS], kernel_start: u64, kernel_end: u64, multiboot_start: u64, multiboot_end: u64,
+                 memory_areas: MemoryAreaIter) -> AreaAllocator {
     let mut allocator = AreaAllocator {
-      next_free: PhysicalPage::containing_address(0),
-      current_area: None,
+      free: bitmap,
+      free_count: 0,
+      managed_pages: 0,
+      next_search: 0,
       areas: memory_areas,
-      kernel_start: PhysicalPage::containing_address(kernel_start),
-      kernel_end: PhysicalPage::containing_address(kernel_end),
-      multiboot_start: PhysicalPage::containing_address(multiboot_start),
-      multiboot_end: PhysicalPage::containing_address(multiboot_end)
+      kernel: (PhysicalPage::containing_address(kernel_start), PhysicalPage::containing_address(kernel_end)),
+      multiboot: (PhysicalPage::containing_address(multiboot_start), PhysicalPage::containing_address(multiboot_end))
     };
-    allocator.choose_next_area();
+
+    for area in allocator.areas.clone() {
+      if area.end_address() > MAX_PHYSICAL_MEMORY {
+        println!("warning: ignoring physical memory above {:#x}", MAX_PHYSICAL_MEMORY);
+      }
+      let end_address = if area.end_address() > MAX_PHYSICAL_MEMORY { MAX_PHYSICAL_MEMORY } else { area.end_address() };
+      if end_address <= area.start_address() {
+        continue;
+      }
+      let first_page = PhysicalPage::containing_address(area.start_address() + PAGE_SIZE - 1);
+      let last_page = PhysicalPage::containing_address(end_address - 1);
+      for page in PhysicalPage::range_inclusive(first_page, last_page) {
+        if allocator.is_reserved(&page) {
+          continue;
+        }
+        let (word, bit) = (page.number / 64, page.number % 64);
+        allocator.free[word] |= 1 << bit;
+        allocator.free_count += 1;
+      }
+    }
+    allocator.managed_pages = allocator.free_count;
     allocator
   }
 
-  fn choose_next_area(&mut self) {
-    self.current_area = self.areas.clone().filter(|area| {
-      let address = area.start_address() + area.size() - 1;
-      PhysicalPage::containing_address(address) >= self.next_free
-    }).min_by_key(|area| area.start_address());
-    if let Some(area) = self.current_area {
-      let first_page = PhysicalPage::containing_address(area.start_address());
-      if self.next_free < first_page {
-        self.next_free = first_page;
-      }
+  pub fn managed_pages(&self) -> usize {
+    self.managed_pages
+  }
+
+  fn is_reserved(&self, page: &PhysicalPage) -> bool {
+    (*page >= self.kernel.0 && *page <= self.kernel.1) || (*page >= self.multiboot.0 && *page <= self.multiboot.1)
+  }
+
+  fn is_usable(&self, page: &PhysicalPage) -> bool {
+    page.number < MAX_PAGES && self.areas.clone().any(|area| {
+      page.start_address() >= area.start_address() && page.start_address() + PAGE_SIZE <= area.end_address()
+    })
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use alloc::boxed::Box;
+  use alloc::vec::Vec;
+  use memory::{Allocator, PhysicalPage, PAGE_SIZE};
+  use multiboot2;
+
+  // Builds a multiboot information structure holding only a memory map with the given
+  // (start, length) areas. It is leaked, as the iterator hands out 'static references.
+  pub fn memory_areas(areas: &[(u64, u64)]) -> MemoryAreaIter {
+    let mut info: Vec<u64> = Vec::new();
+    let map_size = 16 + 24 * areas.len() as u64;
+    let total_size = 8 + map_size + 8;
+    info.push(total_size);
+    info.push(6 | map_size << 32); // memory map tag
+    info.push(24); // entry size, version 0
+    for &(start, length) in areas {
+      info.push(start);
+      info.push(length);
+      info.push(1); // available
     }
+    info.push(8 << 32); // end tag
+    let info = Box::leak(info.into_boxed_slice());
+    let boot_info = unsafe { multiboot2::load(info.as_ptr() as usize) };
+    boot_info.memory_map_tag().unwrap().memory_areas()
+  }
+
+  pub fn bitmap() -> &'static mut [u64; BITMAP_WORDS] {
+    Box::leak(Box::new([0; BITMAP_WORDS]))
+  }
+
+  fn allocator(areas: &[(u64, u64)]) -> AreaAllocator {
+    // The kernel is at 1 MiB and the multiboot information just after it.
+    AreaAllocator::with_bitmap(bitmap(), 0x10_0000, 0x10_1fff, 0x10_2000, 0x10_2fff, memory_areas(areas))
+  }
+
+  #[test]
+  fn skips_the_kernel_and_multiboot_information() {
+    let mut allocator = allocator(&[(0x10_0000, 0x4000)]);
+    assert_eq!(allocator.managed_pages(), 1);
+    let page = allocator.allocate().unwrap();
+    assert_eq!(page.start_address(), 0x10_3000);
+    assert!(allocator.allocate().is_none());
+  }
+
+  #[test]
+  fn ignores_partial_pages_and_memory_above_the_limit() {
+    let mut allocator = allocator(&[(0x800, 0x1800), (MAX_PHYSICAL_MEMORY - PAGE_SIZE, 2 * PAGE_SIZE)]);
+    assert_eq!(allocator.allocate().unwrap().start_address(), 0x1000);
+    assert_eq!(allocator.allocate().unwrap().start_address(), MAX_PHYSICAL_MEMORY - PAGE_SIZE);
+    assert!(allocator.allocate().is_none());
+  }
+
+  #[test]
+  fn reuses_freed_pages() {
+    let mut allocator = allocator(&[(0, 0x3000)]);
+    let first = allocator.allocate().unwrap();
+    let second = allocator.allocate().unwrap();
+    assert!(first != second);
+    allocator.deallocate(first);
+    assert_eq!(allocator.allocate().unwrap().start_address(), 0);
+    allocator.deallocate(second);
+  }
+
+  #[test]
+  #[should_panic(expected = "double free")]
+  fn rejects_double_frees() {
+    let mut allocator = allocator(&[(0, 0x3000)]);
+    let page = allocator.allocate().unwrap();
+    allocator.deallocate(PhysicalPage { number: page.number });
+    allocator.deallocate(page);
+  }
+
+  #[test]
+  #[should_panic(expected = "belongs to the kernel")]
+  fn rejects_kernel_pages() {
+    let mut allocator = allocator(&[(0x10_0000, 0x4000)]);
+    allocator.deallocate(PhysicalPage::containing_address(0x10_1000));
+  }
+
+  #[test]
+  #[should_panic(expected = "not usable memory")]
+  fn rejects_pages_outside_memory_areas() {
+    let mut allocator = allocator(&[(0, 0x3000)]);
+    allocator.deallocate(PhysicalPage::containing_address(0x5000));
   }
 }
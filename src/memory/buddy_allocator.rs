@@ -0,0 +1,236 @@
+use memory::{PhysicalPage, Allocator, ContiguousAllocator};
+use memory::area_allocator::MAX_PHYSICAL_MEMORY;
+use memory::PAGE_SIZE;
+
+// Blocks of order n are 2^n pages long, so the largest block is 1 GiB.
+pub const MAX_ORDER: usize = 18;
+const MAX_PAGES: usize = (MAX_PHYSICAL_MEMORY / PAGE_SIZE) as usize;
+// Order n needs MAX_PAGES >> n bits; this comfortably covers the sum over all orders.
+const BITMAP_WORDS: usize = 2 * (MAX_PAGES / 64) + MAX_ORDER + 1;
+
+// One bit per block per order, set if that block is free at that order. A free page is only ever
+// recorded at one order: the highest one it could be merged up to.
+static mut FREE_BITMAPS: [u64; BITMAP_WORDS] = [0; BITMAP_WORDS];
+
+pub struct BuddyAllocator {
+  bitmaps: &'static mut [u64; BITMAP_WORDS],
+  offsets: [usize; MAX_ORDER + 1],
+  free_blocks: [usize; MAX_ORDER + 1],
+  next_search: [usize; MAX_ORDER + 1]
+}
+
+impl Allocator for BuddyAllocator {
+  fn allocate(&mut self) -> Option<PhysicalPage> {
+    self.allocate_order(0)
+  }
+
+  fn deallocate(&mut self, page: PhysicalPage) {
+    self.deallocate_order(page, 0)
+  }
+}
+
+impl ContiguousAllocator for BuddyAllocator {
+  fn allocate_order(&mut self, order: usize) -> Option<PhysicalPage> {
+    if order > MAX_ORDER {
+      return None;
+    }
+    let mut current_order = match (order..MAX_ORDER + 1).find(|&o| self.free_blocks[o] > 0) {
+      Some(o) => o,
+      None => return None
+    };
+    let mut block = self.take_free_block(current_order);
+    // Split the block in half until it is the right size, freeing the upper half each time.
+    while current_order > order {
+      current_order -= 1;
+      block *= 2;
+      self.set_free(current_order, block + 1);
+    }
+    Some(PhysicalPage { number: block << order })
+  }
+
+  fn deallocate_order(&mut self, page: PhysicalPage, order: usize) {
+    assert!(order <= MAX_ORDER, "order {} is larger than the maximum of {}", order, MAX_ORDER);
+    assert!(page.number % (1 << order) == 0, "physical page {:#x} is not aligned for order {}", page.start_address(), order);
+    assert!(page.number < MAX_PAGES, "physical page {:#x} is above the highest tracked address", page.start_address());
+    // A page that is already free is recorded in the block containing it at its order or above,
+    // which the merging below would otherwise go on to free a second time.
+    for ancestor_order in order..MAX_ORDER + 1 {
+      assert!(!self.is_free(ancestor_order, page.number >> ancestor_order), "double free of physical page {:#x}", page.start_address());
+    }
+    // Freeing a block that has part of it free already is just as bad, but checking every smaller
+    // block inside it is too slow to do outside of debug builds.
+    if cfg!(debug_assertions) {
+      for descendant_order in 0..order {
+        let first = page.number >> descendant_order;
+        let count = 1 << (order - descendant_order);
+        assert!(!(first..first + count).any(|block| self.is_free(descendant_order, block)),
+                "double free of part of physical block {:#x} of order {}", page.start_address(), order);
+      }
+    }
+    let mut block = page.number >> order;
+    let mut current_order = order;
+    // Merge with the buddy for as long as it is also free.
+    while current_order < MAX_ORDER && self.is_free(current_order, block ^ 1) {
+      self.clear_free(current_order, block ^ 1);
+      block /= 2;
+      current_order += 1;
+    }
+    self.set_free(current_order, block);
+  }
+}
+
+impl BuddyAllocator {
+  // Takes every page the given allocator has left. Must only be called once, as every
+  // BuddyAllocator shares the same bitmaps.
+  pub fn new<A>(allocator: &mut A) -> BuddyAllocator where A: Allocator {
+    BuddyAllocator::with_bitmaps(unsafe { &mut FREE_BITMAPS }, allocator)
+  }
+
+  fn with_bitmaps<A>(bitmaps: &'static mut [u64; BITMAP_WORDS], allocator: &mut A) -> BuddyAllocator where A: Allocator {
+    let mut offsets = [0; MAX_ORDER + 1];
+    let mut offset = 0;
+    for order in 0..MAX_ORDER + 1 {
+      offsets[order] = offset;
+      offset += Self::words_for_order(order);
+    }
+    assert!(offset <= BITMAP_WORDS);
+
+    let mut buddy = BuddyAllocator {
+      bitmaps,
+      offsets,
+      free_blocks: [0; MAX_ORDER + 1],
+      next_search: [0; MAX_ORDER + 1]
+    };
+    while let Some(page) = allocator.allocate() {
+      buddy.deallocate(page);
+    }
+    buddy
+  }
+
+  pub fn free_pages(&self) -> usize {
+    self.free_blocks.iter().enumerate().map(|(order, count)| count << order).sum()
+  }
+
+  fn words_for_order(order: usize) -> usize {
+    ((MAX_PAGES >> order) + 63) / 64
+  }
+
+  fn is_free(&self, order: usize, block: usize) -> bool {
+    if block >= MAX_PAGES >> order {
+      return false;
+    }
+    let word = self.offsets[order] + block / 64;
+    self.bitmaps[word] & (1 << (block % 64)) != 0
+  }
+
+  fn set_free(&mut self, order: usize, block: usize) {
+    let word = self.offsets[order] + block / 64;
+    self.bitmaps[word] |= 1 << (block % 64);
+    self.free_blocks[order] += 1;
+    if block / 64 < self.next_search[order] {
+      self.next_search[order] = block / 64;
+    }
+  }
+
+  fn clear_free(&mut self, order: usize, block: usize) {
+    let word = self.offsets[order] + block / 64;
+    self.bitmaps[word] &= !(1 << (block % 64));
+    self.free_blocks[order] -= 1;
+  }
+
+  fn take_free_block(&mut self, order: usize) -> usize {
+    let words = Self::words_for_order(order);
+    let start = self.next_search[order];
+    for i in (start..words).chain(0..start) {
+      let word = self.bitmaps[self.offsets[order] + i];
+      if word != 0 {
+        let block = i * 64 + word.trailing_zeros() as usize;
+        self.clear_free(order, block);
+        self.next_search[order] = i;
+        return block;
+      }
+    }
+    panic!("{} free blocks of order {} but the bitmap is empty", self.free_blocks[order], order);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use alloc::boxed::Box;
+  use alloc::vec::Vec;
+
+  // Hands out the given pages, for the buddy allocator to take over.
+  struct Pages(Vec<usize>);
+
+  impl Allocator for Pages {
+    fn allocate(&mut self) -> Option<PhysicalPage> {
+      self.0.pop().map(|number| PhysicalPage { number })
+    }
+
+    fn deallocate(&mut self, _page: PhysicalPage) {
+      unreachable!();
+    }
+  }
+
+  fn allocator(pages: Vec<usize>) -> BuddyAllocator {
+    BuddyAllocator::with_bitmaps(Box::leak(Box::new([0; BITMAP_WORDS])), &mut Pages(pages))
+  }
+
+  #[test]
+  fn merges_pages_into_blocks() {
+    let buddy = allocator((0..16).collect());
+    assert_eq!(buddy.free_pages(), 16);
+    assert!(buddy.is_free(4, 0));
+    assert!(!buddy.is_free(3, 0) && !buddy.is_free(3, 1));
+  }
+
+  #[test]
+  fn splits_blocks_and_merges_them_back() {
+    let mut buddy = allocator((0..16).collect());
+    let page = buddy.allocate().unwrap();
+    assert_eq!(page.number, 0);
+    assert_eq!(buddy.free_pages(), 15);
+    for order in 0..4 {
+      assert!(buddy.is_free(order, 1));
+    }
+    let block = buddy.allocate_order(2).unwrap();
+    assert_eq!(block.number, 4);
+    buddy.deallocate(page);
+    buddy.deallocate_order(block, 2);
+    assert_eq!(buddy.free_pages(), 16);
+    assert!(buddy.is_free(4, 0));
+  }
+
+  #[test]
+  fn aligns_blocks_to_their_size() {
+    let mut buddy = allocator((3..40).collect());
+    let block = buddy.allocate_order(3).unwrap();
+    assert_eq!(block.number % 8, 0);
+    assert!(block.number >= 8 && block.number + 8 <= 40);
+  }
+
+  #[test]
+  fn fails_when_no_block_is_large_enough() {
+    let mut buddy = allocator(vec![0, 2, 4, 6]);
+    assert!(buddy.allocate_order(1).is_none());
+    assert!(buddy.allocate_order(MAX_ORDER + 1).is_none());
+    assert_eq!(buddy.allocate().unwrap().number % 2, 0);
+  }
+
+  #[test]
+  #[should_panic(expected = "double free")]
+  fn rejects_freeing_a_page_inside_a_free_block() {
+    let mut buddy = allocator((0..16).collect());
+    buddy.deallocate(PhysicalPage { number: 5 });
+  }
+
+  #[test]
+  #[should_panic(expected = "double free of part")]
+  fn rejects_freeing_a_block_containing_a_free_page() {
+    let mut buddy = allocator((0..16).collect());
+    let block = buddy.allocate_order(4).unwrap();
+    buddy.deallocate(PhysicalPage { number: 3 });
+    buddy.deallocate_order(block, 4);
+  }
+}
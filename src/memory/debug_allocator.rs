@@ -0,0 +1,72 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::cmp::max;
+use core::{ptr, slice};
+
+use memory::heap_allocator::HeapStats;
+use memory::slab_allocator::SlabAllocator;
+
+// Freed memory is filled with this, so that reads of freed memory stand out and writes to it can
+// be caught when it is next allocated.
+pub const POISON_FREE: u8 = 0x6b;
+const RED_ZONE: u8 = 0xbb;
+const RED_ZONE_SIZE: usize = 16;
+
+// Wraps the slab allocator with red zones on either side of every allocation, which are checked
+// when the allocation is freed. Selected instead of the plain slab allocator by the heap_debug
+// feature.
+pub struct DebugAllocator {
+  inner: SlabAllocator
+}
+
+impl DebugAllocator {
+  pub const fn new(inner: SlabAllocator) -> DebugAllocator {
+    DebugAllocator { inner }
+  }
+
+  // The range must already be reserved as a virtual memory area that nothing else uses.
+  pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+    self.inner.init(heap_start, heap_size);
+  }
+
+  pub fn stats(&self) -> HeapStats {
+    self.inner.stats()
+  }
+
+  // Each red zone is at least as large as the alignment so that the allocation stays aligned.
+  fn padded_layout(layout: &Layout) -> (Layout, usize) {
+    let red_zone_size = max(RED_ZONE_SIZE, layout.align());
+    let padded = unsafe { Layout::from_size_align_unchecked(layout.size() + 2 * red_zone_size, layout.align()) };
+    (padded, red_zone_size)
+  }
+}
+
+unsafe fn check_red_zone(zone: *const u8, size: usize, allocation: *mut u8, layout: &Layout, position: &str) {
+  let zone = slice::from_raw_parts(zone, size);
+  if let Some(offset) = zone.iter().position(|&byte| byte != RED_ZONE) {
+    panic!("heap corruption: red zone {} the {} byte allocation at {:#x} was overwritten at byte {}",
+           position, layout.size(), allocation as usize, offset);
+  }
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let (padded, red_zone_size) = Self::padded_layout(&layout);
+    let block = self.inner.alloc(padded);
+    if block.is_null() {
+      return block;
+    }
+    let allocation = block.offset(red_zone_size as isize);
+    ptr::write_bytes(block, RED_ZONE, red_zone_size);
+    ptr::write_bytes(allocation.offset(layout.size() as isize), RED_ZONE, red_zone_size);
+    allocation
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    let (padded, red_zone_size) = Self::padded_layout(&layout);
+    let block = ptr.offset(-(red_zone_size as isize));
+    check_red_zone(block, red_zone_size, ptr, &layout, "before");
+    check_red_zone(ptr.offset(layout.size() as isize), red_zone_size, ptr, &layout, "after");
+    ptr::write_bytes(block, POISON_FREE, padded.size());
+    self.inner.dealloc(block, padded);
+  }
+}
@@ -0,0 +1,127 @@
+use memory::paging::{VirtualPage, VirtualPageIter};
+
+const MAX_FREE_RANGES: usize = 32;
+
+// The free pages of a range of virtual memory: those that have never been handed out, and ranges
+// that have been given back, which are reused first. Ranges given back are merged with their
+// neighbours, but if too many are left apart the excess is leaked.
+pub struct FreeRanges {
+  unused: VirtualPageIter,
+  ranges: [Option<(VirtualPage, VirtualPage)>; MAX_FREE_RANGES],
+  // What the pages are used for, for warnings.
+  name: &'static str
+}
+
+impl FreeRanges {
+  pub fn new(range: VirtualPageIter, name: &'static str) -> FreeRanges {
+    FreeRanges { unused: range, ranges: [None; MAX_FREE_RANGES], name }
+  }
+
+  // Takes page_count consecutive pages, returning the first of them.
+  pub fn take(&mut self, page_count: usize) -> Option<VirtualPage> {
+    assert!(page_count > 0);
+    for slot in self.ranges.iter_mut() {
+      if let Some((first, last)) = *slot {
+        let end = first + (page_count - 1);
+        if end <= last {
+          *slot = if end == last { None } else { Some((end + 1, last)) };
+          return Some(first);
+        }
+      }
+    }
+
+    let mut unused = self.unused.clone();
+    let start = unused.next();
+    let end = if page_count == 1 { start } else { unused.nth(page_count - 2) };
+    match (start, end) {
+      (Some(start), Some(_)) => {
+        self.unused = unused;
+        Some(start)
+      },
+      _ => None  // not enough pages
+    }
+  }
+
+  // Gives back the pages from first to last inclusive.
+  pub fn add(&mut self, mut first: VirtualPage, mut last: VirtualPage) {
+    for slot in self.ranges.iter_mut() {
+      if let Some((other_first, other_last)) = *slot {
+        if other_last + 1 == first {
+          first = other_first;
+          *slot = None;
+        }
+        else if last + 1 == other_first {
+          last = other_last;
+          *slot = None;
+        }
+      }
+    }
+    match self.ranges.iter_mut().find(|slot| slot.is_none()) {
+      Some(slot) => *slot = Some((first, last)),
+      None => println!("warning: too many free {} ranges, leaking {:#x}-{:#x}", self.name, first.start_address(), last.start_address())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn page(number: u64) -> VirtualPage {
+    VirtualPage::containing_address(number * 0x1000)
+  }
+
+  fn ranges() -> FreeRanges {
+    FreeRanges::new(VirtualPage::range_inclusive(page(10), page(19)), "test")
+  }
+
+  #[test]
+  fn takes_unused_pages_in_order() {
+    let mut ranges = ranges();
+    assert_eq!(ranges.take(3), Some(page(10)));
+    assert_eq!(ranges.take(1), Some(page(13)));
+    assert_eq!(ranges.take(6), Some(page(14)));
+    assert_eq!(ranges.take(1), None);
+  }
+
+  #[test]
+  fn fails_without_leaking_unused_pages() {
+    let mut ranges = ranges();
+    assert_eq!(ranges.take(11), None);
+    assert_eq!(ranges.take(10), Some(page(10)));
+  }
+
+  #[test]
+  fn reuses_pages_given_back() {
+    let mut ranges = ranges();
+    let first = ranges.take(4).unwrap();
+    ranges.add(first, first + 3);
+    assert_eq!(ranges.take(2), Some(page(10)));
+    assert_eq!(ranges.take(2), Some(page(12)));
+    assert_eq!(ranges.take(1), Some(page(14)));
+  }
+
+  #[test]
+  fn merges_neighbouring_ranges() {
+    let mut ranges = ranges();
+    assert_eq!(ranges.take(10), Some(page(10)));
+    ranges.add(page(10), page(11));
+    ranges.add(page(14), page(15));
+    ranges.add(page(12), page(13));
+    assert_eq!(ranges.take(6), Some(page(10)));
+    assert_eq!(ranges.take(1), None);
+  }
+
+  #[test]
+  fn leaks_ranges_that_do_not_fit() {
+    let mut ranges = FreeRanges::new(VirtualPage::range_inclusive(page(0), page(99)), "test");
+    assert_eq!(ranges.take(100), Some(page(0)));
+    for i in 0..MAX_FREE_RANGES as u64 + 1 {
+      ranges.add(page(i * 2), page(i * 2));
+    }
+    for i in 0..MAX_FREE_RANGES as u64 {
+      assert_eq!(ranges.take(1), Some(page(i * 2)));
+    }
+    assert_eq!(ranges.take(1), None);
+  }
+}
@@ -1,22 +1,137 @@
-use alloc::alloc::{Alloc, AllocErr, Layout};
-use core::ptr::NonNull;
+use alloc::alloc::{Alloc, AllocErr, GlobalAlloc, Layout};
+use core::cmp::max;
+use core::fmt;
+use core::ptr::{self, NonNull};
 use core::sync::atomic::{AtomicUsize, Ordering};
 
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use memory::{self, PAGE_SIZE};
+
+// The heap grows by at least this much at a time, so that it isn't extended for every allocation.
+const HEAP_GROWTH_STEP: usize = 64 * 1024;
+
+// A linked list heap which grows its virtual memory whenever an allocation doesn't fit, until it
+// reaches max_size. Growing takes no other locks, and the pages are backed by the page fault handler
+// without the memory controller, so the heap can be used while the controller is locked. The lock is
+// held with interrupts disabled, so that interrupt handlers can allocate too. Page faults still get
+// through, but their handler never touches the heap.
+pub struct GrowableHeap {
+  heap: Mutex<Heap>,
+  max_size: usize,
+  used: AtomicUsize
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+  pub size: usize,
+  pub used: usize
+}
+
+impl fmt::Display for HeapStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "HeapSize:   {:>10} KiB", self.size / 1024)?;
+    writeln!(f, "HeapUsed:   {:>10} KiB", self.used / 1024)?;
+    write!(f, "HeapFree:   {:>10} KiB", (self.size - self.used) / 1024)
+  }
+}
+
+impl GrowableHeap {
+  pub const fn empty(max_size: usize) -> GrowableHeap {
+    GrowableHeap { heap: Mutex::new(Heap::empty()), max_size, used: AtomicUsize::new(0) }
+  }
+
+  pub fn stats(&self) -> HeapStats {
+    let size = interrupts::without_interrupts(|| self.heap.lock().size());
+    HeapStats { size, used: self.used.load(Ordering::Relaxed) }
+  }
+
+  // The range must already be reserved as a virtual memory area that nothing else uses.
+  pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+    interrupts::without_interrupts(|| self.heap.lock().init(heap_start, heap_size));
+  }
+
+  unsafe fn grow(&self, heap: &mut Heap, layout: &Layout) -> bool {
+    let by = align_up(max(layout.size() + layout.align(), HEAP_GROWTH_STEP), PAGE_SIZE as usize);
+    if heap.size() + by > self.max_size {
+      return false;
+    }
+    memory::extend_heap(by / PAGE_SIZE as usize);
+    heap.extend(by);
+    true
+  }
+}
+
+unsafe impl GlobalAlloc for GrowableHeap {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let allocation = interrupts::without_interrupts(|| {
+      let mut heap = self.heap.lock();
+      let allocation = heap.allocate_first_fit(layout);
+      if allocation.is_err() && self.grow(&mut heap, &layout) {
+        heap.allocate_first_fit(layout)
+      }
+      else {
+        allocation
+      }
+    });
+    match allocation {
+      Ok(allocation) => {
+        self.used.fetch_add(layout.size(), Ordering::Relaxed);
+        allocation.as_ptr()
+      },
+      Err(_) => ptr::null_mut()
+    }
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    interrupts::without_interrupts(|| self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout));
+    self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+  }
+}
+
 #[derive(Debug)]
 pub struct BumpAllocator {
   heap_start: usize,
   heap_end: usize,
-  next: AtomicUsize
+  next: AtomicUsize,
+  high_water_mark: AtomicUsize
 }
 
 impl BumpAllocator {
   pub const fn new(heap_start: usize, heap_end: usize) -> Self {
-    Self { heap_start, heap_end, next: AtomicUsize::new(heap_start) }
+    Self { heap_start, heap_end, next: AtomicUsize::new(heap_start), high_water_mark: AtomicUsize::new(heap_start) }
   }
-}
 
-unsafe impl<'a> Alloc for &'a BumpAllocator {
-  unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+  // Frees everything allocated so far, so the allocator can be reused as a scoped arena. Unsafe
+  // because any allocations still in use will be handed out again.
+  pub unsafe fn reset(&self) {
+    self.next.store(self.heap_start, Ordering::Relaxed);
+  }
+
+  pub fn used(&self) -> usize {
+    self.next.load(Ordering::Relaxed) - self.heap_start
+  }
+
+  pub fn high_water_mark(&self) -> usize {
+    self.high_water_mark.load(Ordering::Relaxed) - self.heap_start
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.heap_end - self.heap_start
+  }
+
+  pub fn stats(&self) -> HeapStats {
+    HeapStats { size: self.capacity(), used: self.used() }
+  }
+
+  // The range is fixed when the allocator is created, so this only checks that it is the same one.
+  pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+    assert!(heap_start == self.heap_start && heap_start + heap_size == self.heap_end, "bump allocator was created for a different heap");
+  }
+
+  fn allocate(&self, layout: &Layout) -> Option<NonNull<u8>> {
     loop {
       let current_next = self.next.load(Ordering::Relaxed);
       let start = align_up(current_next, layout.align());
@@ -24,17 +139,53 @@ unsafe impl<'a> Alloc for &'a BumpAllocator {
       if end <= self.heap_end {
         let next_now = self.next.compare_and_swap(current_next, end, Ordering::Relaxed);
         if next_now == current_next {
-          return Ok(NonNull::new(start as *mut u8).unwrap());
+          self.raise_high_water_mark(end);
+          return NonNull::new(start as *mut u8);
         }
       }
       else {
-        return Err(AllocErr);
+        return None;
+      }
+    }
+  }
+
+  fn raise_high_water_mark(&self, end: usize) {
+    let mut mark = self.high_water_mark.load(Ordering::Relaxed);
+    while mark < end {
+      let previous = self.high_water_mark.compare_and_swap(mark, end, Ordering::Relaxed);
+      if previous == mark {
+        break;
       }
+      mark = previous;
     }
   }
 
+  // Only the most recent allocation can actually be freed. Anything else stays allocated until
+  // the next reset.
+  fn free(&self, ptr: NonNull<u8>, layout: &Layout) {
+    let start = ptr.as_ptr() as usize;
+    let end = start + layout.size();
+    self.next.compare_and_swap(end, start, Ordering::Relaxed);
+  }
+}
+
+unsafe impl<'a> Alloc for &'a BumpAllocator {
+  unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+    self.allocate(&layout).ok_or(AllocErr)
+  }
+
   unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
-    println!("warning: deallocation of memory is unimplemented");
+    self.free(ptr, &layout)
+  }
+}
+
+unsafe impl GlobalAlloc for BumpAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    self.allocate(&layout).map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    self.free(NonNull::new_unchecked(ptr), &layout)
   }
 }
 
@@ -0,0 +1,102 @@
+use core::cmp;
+use core::ops::{Deref, DerefMut};
+use core::ptr::Unique;
+
+use multiboot2::MemoryAreaIter;
+
+use memory::{self, Allocator, PhysicalPage, PAGE_SIZE};
+use memory::area_allocator::MAX_PHYSICAL_MEMORY;
+use memory::free_ranges::FreeRanges;
+use memory::paging::{ActivePageTable, EntryFlags, PhysicalAddress, VirtualAddress, VirtualPage, VirtualPageIter};
+
+// Hands out pages of the virtual window that device memory is mapped into.
+pub struct IoRemapper {
+  pages: FreeRanges,
+  // RAM, which must never be mapped as device memory.
+  memory_areas: MemoryAreaIter
+}
+
+impl IoRemapper {
+  pub fn new(page_range: VirtualPageIter, memory_areas: MemoryAreaIter) -> IoRemapper {
+    IoRemapper { pages: FreeRanges::new(page_range, "device memory"), memory_areas }
+  }
+
+  // Maps the physical pages covering size bytes from physical, returning the address that
+  // physical was mapped at, along with the first page and number of pages used. Returns None for
+  // RAM that the page allocator manages, which may be handed out to anything, and which the
+  // physical memory map may already map as write-back memory. Mapping a page with two different
+  // memory types is undefined.
+  pub fn map<A>(&mut self, active_table: &mut ActivePageTable, allocator: &mut A, physical: PhysicalAddress, size: usize, flags: EntryFlags) -> Option<(VirtualAddress, VirtualPage, usize)> where A: Allocator {
+    assert!(size > 0, "cannot map an empty range of device memory");
+    let physical_start = PhysicalPage::containing_address(physical);
+    let physical_end = PhysicalPage::containing_address(physical + size as u64 - 1);
+    if self.is_ram(&physical_start, &physical_end) {
+      return None;
+    }
+    let page_count = physical_end.number - physical_start.number + 1;
+    self.pages.take(page_count).map(|start| {
+      let pages = VirtualPage::range_inclusive(start, start + (page_count - 1));
+      for (page, physical_page) in pages.zip(PhysicalPage::range_inclusive(physical_start, physical_end)) {
+        active_table.map_to(page, physical_page, flags | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, allocator);
+      }
+      (start.start_address() + physical % PAGE_SIZE, start, page_count)
+    })
+  }
+
+  // Whether any page from start to end inclusive is one the page allocator manages, which is every
+  // whole page of RAM below MAX_PHYSICAL_MEMORY.
+  fn is_ram(&self, start: &PhysicalPage, end: &PhysicalPage) -> bool {
+    self.memory_areas.clone().any(|area| {
+      let ram_start = (area.start_address() + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+      let ram_end = cmp::min(area.end_address(), MAX_PHYSICAL_MEMORY) / PAGE_SIZE * PAGE_SIZE;
+      start.start_address() < ram_end && end.start_address() + PAGE_SIZE > ram_start
+    })
+  }
+
+  pub fn unmap<A>(&mut self, active_table: &mut ActivePageTable, allocator: &mut A, start: VirtualPage, page_count: usize) where A: Allocator {
+    let end = start + (page_count - 1);
+    for page in VirtualPage::range_inclusive(start, end) {
+      active_table.unmap_without_free(page, allocator);
+    }
+    self.pages.add(start, end);
+  }
+}
+
+// Device memory mapped by MemoryController::ioremap, which is unmapped again when this is dropped.
+// Registers should be declared with Volatile so that the compiler doesn't elide or merge accesses.
+pub struct IoMapping<T: ?Sized> {
+  pointer: Unique<T>,
+  start: VirtualPage,
+  page_count: usize
+}
+
+impl<T: ?Sized> IoMapping<T> {
+  pub fn new(pointer: Unique<T>, start: VirtualPage, page_count: usize) -> IoMapping<T> {
+    IoMapping { pointer, start, page_count }
+  }
+
+  pub fn address(&self) -> VirtualAddress {
+    self.pointer.as_ptr() as *const u8 as VirtualAddress
+  }
+}
+
+impl<T: ?Sized> Deref for IoMapping<T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { self.pointer.as_ref() }
+  }
+}
+
+impl<T: ?Sized> DerefMut for IoMapping<T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { self.pointer.as_mut() }
+  }
+}
+
+// Like a Stack, an IoMapping must not be dropped while the memory controller is locked.
+impl<T: ?Sized> Drop for IoMapping<T> {
+  fn drop(&mut self) {
+    memory::controller_for_drop("I/O mapping").iounmap(self.start, self.page_count);
+  }
+}
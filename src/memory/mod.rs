@@ -1,18 +1,42 @@
 mod area_allocator;
+mod buddy_allocator;
+pub mod debug_allocator;
+mod free_ranges;
 pub mod heap_allocator;
+mod mmio;
 mod paging;
+mod shared_pages;
+pub mod slab_allocator;
 mod stack_allocator;
+mod user;
+mod vma;
 
-use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use core::{fmt, mem, ptr, slice};
+use core::ptr::Unique;
+use core::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT, Ordering};
 
 use multiboot2::BootInformation;
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::instructions::interrupts;
 
-use super::{HEAP_START, HEAP_SIZE};
-use self::paging::{PhysicalAddress, VirtualPage, ActivePageTable};
-use self::paging::EntryFlags;
-pub use self::paging::remap_kernel;
+use super::{HEAP_START, HEAP_SIZE, HEAP_MAX_SIZE, MMIO_START, MMIO_SIZE};
+use self::heap_allocator::HeapStats;
+use self::mmio::IoRemapper;
+pub use self::mmio::IoMapping;
+pub use self::paging::{PhysicalAddress, VirtualAddress};
+use self::paging::{VirtualPage, ActivePageTable, TemporaryPage};
+use self::paging::{physical_to_virtual, map_in_active_table};
+#[cfg(feature = "physical_memory_map")]
+use self::paging::{InactivePageTable, flush_address_space};
+use self::shared_pages::SharedPages;
+pub use self::paging::{EntryFlags, MemoryType};
+pub use self::paging::{init_pat, init_tlb, global_pages_enabled, pcid_enabled};
+pub use self::paging::{remap_kernel, walk_active_table, page_table_pages, PageWalk};
+use self::paging::kernel_physical_address;
 use self::stack_allocator::StackAllocator;
-pub use self::stack_allocator::Stack;
+pub use self::stack_allocator::{Stack, guard_page_owner};
+pub use self::user::{copy_from_user, copy_to_user, UserCopyError};
+use self::vma::VmaRegistry;
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PhysicalPage {
@@ -26,7 +50,7 @@ impl PhysicalPage {
     PhysicalPage { number: (address / PAGE_SIZE) as usize }
   }
 
-  fn start_address(&self) -> PhysicalAddress {
+  pub fn start_address(&self) -> PhysicalAddress {
     self.number as u64 * PAGE_SIZE
   }
 
@@ -64,11 +88,118 @@ pub trait Allocator {
   fn deallocate(&mut self, page: PhysicalPage);
 }
 
+// An allocator that can hand out 2^order physically contiguous pages, aligned to their size.
+pub trait ContiguousAllocator: Allocator {
+  fn allocate_order(&mut self, order: usize) -> Option<PhysicalPage>;
+  fn deallocate_order(&mut self, page: PhysicalPage, order: usize);
+}
+
 pub use self::area_allocator::AreaAllocator;
+pub use self::buddy_allocator::BuddyAllocator;
 
 static MEMORY_INITIALISED: AtomicBool = ATOMIC_BOOL_INIT;
+static MEMORY_CONTROLLER: Once<Mutex<MemoryController>> = Once::new();
+static PHYSICAL_ALLOCATOR: Once<Mutex<BuddyAllocator>> = Once::new();
+
+// The end of the heap, which grows as the heap does. Heap pages are backed on first access like
+// those of a virtual memory area, but by the page fault handler itself rather than through the
+// MemoryController, so that heap memory can be touched while the controller is locked.
+static HEAP_END: AtomicUsize = AtomicUsize::new(0);
+
+// A handle to the physical page allocator, which lives outside the MemoryController so that the
+// page fault handler can use it while the controller is locked. It is locked for each call, with
+// interrupts disabled, and nothing else is ever locked while it is held.
+struct PhysicalAllocator;
+
+impl PhysicalAllocator {
+  fn with<F, T>(f: F) -> T where F: FnOnce(&mut BuddyAllocator) -> T {
+    interrupts::without_interrupts(|| {
+      f(&mut PHYSICAL_ALLOCATOR.try().expect("memory::init() has not been called").lock())
+    })
+  }
+
+  fn free_pages(&self) -> usize {
+    Self::with(|allocator| allocator.free_pages())
+  }
+}
+
+impl Allocator for PhysicalAllocator {
+  fn allocate(&mut self) -> Option<PhysicalPage> {
+    Self::with(|allocator| allocator.allocate())
+  }
+
+  fn deallocate(&mut self, page: PhysicalPage) {
+    Self::with(|allocator| allocator.deallocate(page))
+  }
+}
+
+impl ContiguousAllocator for PhysicalAllocator {
+  fn allocate_order(&mut self, order: usize) -> Option<PhysicalPage> {
+    Self::with(|allocator| allocator.allocate_order(order))
+  }
+
+  fn deallocate_order(&mut self, page: PhysicalPage, order: usize) {
+    Self::with(|allocator| allocator.deallocate_order(page, order))
+  }
+}
+
+// Grows the heap by the given number of pages, which are backed as they are touched. This takes no
+// locks, so the heap can call it with its own lock held.
+pub fn extend_heap(additional_pages: usize) {
+  let by = additional_pages * PAGE_SIZE as usize;
+  let end = HEAP_END.fetch_add(by, Ordering::Relaxed) + by;
+  assert!(end as u64 <= HEAP_START + HEAP_MAX_SIZE, "heap extended past its maximum size");
+}
+
+// Locks are taken in this order: the heap's (the slab allocator's, then the GrowableHeap's), the
+// controller, then the physical allocator. The heap never takes the controller's lock, so the
+// controller may allocate. Exception handlers must not use this, as the code they interrupted may
+// be holding the lock.
+pub fn controller() -> MutexGuard<'static, MemoryController> {
+  MEMORY_CONTROLLER.try().expect("memory::init() has not been called").lock()
+}
+
+// For destructors that hand memory back to the controller, such as those of Stack and IoMapping.
+// These must not be dropped while the controller is locked, which would deadlock, so this panics
+// instead of waiting.
+fn controller_for_drop(what: &str) -> MutexGuard<'static, MemoryController> {
+  match MEMORY_CONTROLLER.try().expect("memory::init() has not been called").try_lock() {
+    Some(controller) => controller,
+    None => panic!("{} dropped while the memory controller is locked", what)
+  }
+}
+
+// Called by the page fault handler. Returns whether the fault was resolved by mapping a page.
+pub fn handle_page_fault(address: VirtualAddress) -> bool {
+  if address >= HEAP_START && address < HEAP_END.load(Ordering::Relaxed) as u64 {
+    return map_heap_page(address);
+  }
+  match MEMORY_CONTROLLER.try().and_then(|controller| controller.try_lock()) {
+    Some(mut controller) => controller.handle_page_fault(address),
+    None => false
+  }
+}
+
+// The heap's page tables are all created by init, so this only has to fill in a P1 entry.
+fn map_heap_page(address: VirtualAddress) -> bool {
+  let page = VirtualPage::containing_address(address);
+  if !map_in_active_table(page, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, &mut PhysicalAllocator) {
+    return false;
+  }
+  unsafe { ptr::write_bytes(page.start_address() as *mut u8, 0, PAGE_SIZE as usize) };
+  true
+}
 
-pub fn init(boot_info: &BootInformation) -> MemoryController {
+// Called by the page fault handler for writes to present pages. Returns whether the fault was
+// resolved by copying a copy-on-write page.
+pub fn handle_write_fault(address: VirtualAddress) -> bool {
+  match MEMORY_CONTROLLER.try().and_then(|controller| controller.try_lock()) {
+    Some(mut controller) => controller.copy_on_write(address),
+    None => false
+  }
+}
+
+pub fn init(boot_info: &BootInformation) {
   let already_initialised = MEMORY_INITIALISED.swap(true, Ordering::Relaxed);
   assert!(!already_initialised, "attempted to call memory::init() a second time");
   let memory_map_tag = boot_info.memory_map_tag().expect("memory map tag not found");
@@ -86,50 +217,252 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
 
   let kernel_start = elf_sections_tag.sections()
                                      .filter(|s| s.is_allocated())
-                                     .map(|s| s.start_address())
+                                     .map(|s| kernel_physical_address(s.start_address()))
                                      .min()
                                      .unwrap();
   let kernel_end = elf_sections_tag.sections()
                                    .filter(|s| s.is_allocated())
-                                   .map(|s| s.end_address())
+                                   .map(|s| kernel_physical_address(s.end_address()))
                                    .max()
                                    .unwrap();
-  let multiboot_start = boot_info.start_address() as u64;
-  let multiboot_end = boot_info.end_address() as u64;
+  let multiboot_start = kernel_physical_address(boot_info.start_address() as u64);
+  let multiboot_end = kernel_physical_address(boot_info.end_address() as u64);
   println!("kernel: {:#x}-{:#x}, multiboot: {:#x}-{:#x}", kernel_start, kernel_end, multiboot_start, multiboot_end);
 
   print!("Setting up memory allocator... ");
-  let mut allocator = AreaAllocator::new(kernel_start as u64, kernel_end as u64, multiboot_start, multiboot_end, memory_map_tag.memory_areas());
+  let mut area_allocator = AreaAllocator::new(kernel_start as u64, kernel_end as u64, multiboot_start, multiboot_end, memory_map_tag.memory_areas());
+  // Only memory that can be allocated counts, not the kernel itself or anything above 4 GiB.
+  let total_memory = area_allocator.managed_pages() as u64 * PAGE_SIZE;
   println!("done.");
 
   println!("Remapping kernel sections...");
-  let mut active_table = remap_kernel(&mut allocator, boot_info);
+  let (mut active_table, temporary_page) = remap_kernel(&mut area_allocator, boot_info);
 
-  let heap_start_page = VirtualPage::containing_address(HEAP_START);
-  let heap_end_page = VirtualPage::containing_address(HEAP_START + HEAP_SIZE);
-  for page in VirtualPage::range_inclusive(heap_start_page, heap_end_page) {
-    active_table.map(page, EntryFlags::WRITABLE, &mut allocator);
+  print!("Setting up buddy allocator... ");
+  PHYSICAL_ALLOCATOR.call_once(|| Mutex::new(BuddyAllocator::new(&mut area_allocator)));
+  let mut allocator = PhysicalAllocator;
+  println!("done ({} pages free).", allocator.free_pages());
+
+  // Otherwise page tables are only ever reached through the recursive mapping.
+  if cfg!(feature = "physical_memory_map") {
+    print!("Mapping physical memory... ");
+    paging::map_physical_memory(&mut active_table, memory_map_tag.memory_areas(), &mut allocator);
+    println!("done.");
   }
 
+  // The heap is mapped on demand by the page fault handler as it gets used. Nothing is ever mapped
+  // in the page below it, so that page and whatever lies beyond the heap's current size act as
+  // guard pages. Its whole range is reserved, and its page tables created, up front.
+  let mut vmas = VmaRegistry::new();
+  let heap_start_page = VirtualPage::containing_address(HEAP_START);
+  let heap_max_end_page = VirtualPage::containing_address(HEAP_START + HEAP_MAX_SIZE - 1);
+  vmas.reserve_unbacked(heap_start_page, heap_max_end_page, "heap");
+  active_table.create_tables(heap_start_page, heap_max_end_page, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, &mut allocator);
+  HEAP_END.store((HEAP_START + HEAP_SIZE) as usize, Ordering::Relaxed);
+
+  // A page that is never mapped separates the heap's maximum extent from the stacks after it.
+  let heap_guard_page = heap_max_end_page + 1;
+  vmas.reserve_unbacked(heap_guard_page, heap_guard_page, "heap guard");
+
   let stack_allocator = {
-    let start = heap_end_page + 1;
+    let start = heap_guard_page + 1;
     let end = start + 100;
     let range = VirtualPage::range_inclusive(start, end);
     StackAllocator::new(range)
   };
 
-  MemoryController { active_table, allocator, stack_allocator }
+  let io_remapper = {
+    let start = VirtualPage::containing_address(MMIO_START);
+    let end = VirtualPage::containing_address(MMIO_START + MMIO_SIZE - 1);
+    IoRemapper::new(VirtualPage::range_inclusive(start, end), memory_map_tag.memory_areas())
+  };
+
+  let shared_pages = unsafe { SharedPages::new() };
+
+  MEMORY_CONTROLLER.call_once(|| Mutex::new(MemoryController { active_table, temporary_page, allocator, stack_allocator, vmas, io_remapper, shared_pages, total_memory }));
 }
 
 pub struct MemoryController {
   active_table: ActivePageTable,
-  allocator: AreaAllocator,
-  stack_allocator: StackAllocator
+  temporary_page: TemporaryPage,
+  allocator: PhysicalAllocator,
+  stack_allocator: StackAllocator,
+  vmas: VmaRegistry,
+  io_remapper: IoRemapper,
+  shared_pages: SharedPages,
+  total_memory: u64
+}
+
+// A snapshot of where physical memory has gone, with sizes in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+  pub total_memory: u64,
+  pub free_memory: u64,
+  pub page_table_memory: u64,
+  pub stacks: usize,
+  pub stack_memory: u64,
+  pub heap: HeapStats
+}
+
+impl fmt::Display for MemoryStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "MemTotal:   {:>10} KiB", self.total_memory / 1024)?;
+    writeln!(f, "MemFree:    {:>10} KiB", self.free_memory / 1024)?;
+    writeln!(f, "PageTables: {:>10} KiB", self.page_table_memory / 1024)?;
+    writeln!(f, "Stacks:     {:>10} KiB ({} stacks)", self.stack_memory / 1024, self.stacks)?;
+    write!(f, "{}", self.heap)
+  }
 }
 
 impl MemoryController {
-  pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
-    let &mut MemoryController { ref mut active_table, ref mut allocator, ref mut stack_allocator } = self;
-    stack_allocator.alloc_stack(active_table, allocator, size_in_pages)
+  pub fn alloc_stack(&mut self, size_in_pages: usize, name: &'static str) -> Option<Stack> {
+    let &mut MemoryController { ref mut active_table, ref mut allocator, ref mut stack_allocator, .. } = self;
+    stack_allocator.alloc_stack(active_table, allocator, size_in_pages, name)
+  }
+
+  fn free_stack(&mut self, stack: &Stack) {
+    let &mut MemoryController { ref mut active_table, ref mut allocator, ref mut stack_allocator, ref mut vmas, ref mut shared_pages, .. } = self;
+    stack_allocator.free_stack(active_table, &mut shared_pages.unsharing(allocator), vmas, stack);
+  }
+
+  pub fn alloc_lazy_stack(&mut self, size_in_pages: usize, name: &'static str) -> Option<Stack> {
+    let &mut MemoryController { ref mut stack_allocator, ref mut vmas, .. } = self;
+    stack_allocator.alloc_lazy_stack(vmas, size_in_pages, name)
+  }
+
+  // Reserves a range of virtual memory which is backed by zeroed pages as it is touched.
+  pub fn reserve(&mut self, start: VirtualAddress, size_in_pages: usize, flags: EntryFlags, name: &'static str) {
+    assert!(size_in_pages > 0);
+    let start_page = VirtualPage::containing_address(start);
+    self.vmas.reserve(start_page, start_page + (size_in_pages - 1), flags, name);
+  }
+
+  // Releases a range reserved with reserve, unmapping any pages of it that were touched.
+  pub fn release(&mut self, start: VirtualAddress) {
+    let area = self.vmas.release(VirtualPage::containing_address(start))
+                        .expect("no virtual memory area starts at this address");
+    let &mut MemoryController { ref mut active_table, ref mut allocator, ref mut shared_pages, .. } = self;
+    active_table.unmap_range(area.start(), area.end(), &mut shared_pages.unsharing(allocator));
+  }
+
+  fn handle_page_fault(&mut self, address: VirtualAddress) -> bool {
+    let page = VirtualPage::containing_address(address);
+    let area = match self.vmas.find(page) {
+      Some(area) if area.is_backed() => area,
+      _ => return false
+    };
+    if self.active_table.translate_page(page).is_some() {
+      return false; // not a missing page, so something else went wrong
+    }
+    self.active_table.map(page, area.flags(), &mut self.allocator);
+    unsafe { ptr::write_bytes(page.start_address() as *mut u8, 0, PAGE_SIZE as usize) };
+    true
+  }
+
+  // Creates a copy of the active address space, sharing the pages of its lower half copy-on-write.
+  // This is what fork needs. Both tables have to be edited at once, which takes the physical memory
+  // map.
+  #[cfg(feature = "physical_memory_map")]
+  pub fn clone_address_space(&mut self) -> InactivePageTable {
+    let p4_page = self.allocator.allocate().expect("out of memory");
+    let mut table = InactivePageTable::new_direct(p4_page);
+    {
+      let &mut MemoryController { ref mut active_table, ref mut allocator, ref mut shared_pages, .. } = self;
+      active_table.share_into(&mut table.mapper(), shared_pages, allocator);
+    }
+    flush_address_space();
+    debug_assert!(table.verify(), "cloned page table failed verification");
+    table
+  }
+
+  // Unmaps a page, only freeing its physical page once no other address space maps it.
+  pub fn unmap(&mut self, address: VirtualAddress) {
+    let &mut MemoryController { ref mut active_table, ref mut allocator, ref mut shared_pages, .. } = self;
+    active_table.unmap(VirtualPage::containing_address(address), &mut shared_pages.unsharing(allocator));
+  }
+
+  fn copy_on_write(&mut self, address: VirtualAddress) -> bool {
+    let page = VirtualPage::containing_address(address);
+    let flags = match self.active_table.walk(page) {
+      PageWalk::Mapped { level: 1, flags, .. } if flags.contains(EntryFlags::COPY_ON_WRITE) => flags,
+      _ => return false
+    };
+    let writable_flags = (flags - EntryFlags::COPY_ON_WRITE) | EntryFlags::WRITABLE;
+    let old_page = self.active_table.translate_page(page).unwrap();
+    if !self.shared_pages.is_shared(&old_page) {
+      // Every other address space has already made its own copy.
+      self.active_table.remap(page, old_page, writable_flags);
+      return true;
+    }
+    let new_page = match self.allocator.allocate() {
+      Some(new_page) => new_page,
+      None => return false
+    };
+    let source = page.start_address() as *const u8;
+    match physical_to_virtual(new_page.start_address()) {
+      Some(destination) => unsafe { ptr::copy_nonoverlapping(source, destination as *mut u8, PAGE_SIZE as usize) },
+      None => {
+        let destination = self.temporary_page.map(new_page.clone(), &mut self.active_table);
+        unsafe { ptr::copy_nonoverlapping(source, destination as *mut u8, PAGE_SIZE as usize) };
+        self.temporary_page.unmap(&mut self.active_table);
+      }
+    }
+    self.shared_pages.release(&old_page);
+    self.active_table.remap(page, new_page, writable_flags);
+    true
+  }
+
+  // Stack memory counts the virtual size of each stack, even where lazy stacks are not yet mapped.
+  pub fn stats(&self) -> MemoryStats {
+    MemoryStats {
+      total_memory: self.total_memory,
+      free_memory: self.allocator.free_pages() as u64 * PAGE_SIZE,
+      page_table_memory: page_table_pages() as u64 * PAGE_SIZE,
+      stacks: self.stack_allocator.stacks(),
+      stack_memory: self.stack_allocator.stack_pages() as u64 * PAGE_SIZE,
+      heap: ::HEAP_ALLOCATOR.stats()
+    }
+  }
+
+  pub fn dump_page_table(&self) {
+    self.active_table.dump();
+  }
+
+  pub fn verify_page_table(&self) -> bool {
+    self.active_table.verify(&self.active_table.p4_page())
+  }
+
+  // Maps device registers, or any other physical memory that isn't managed by the page allocator,
+  // as a T. Registers usually want MemoryType::Uncached, and framebuffers WriteCombining.
+  pub fn ioremap<T>(&mut self, physical: PhysicalAddress, memory_type: MemoryType) -> Option<IoMapping<T>> {
+    self.ioremap_bytes(physical, mem::size_of::<T>(), memory_type).map(|(address, start, page_count)| {
+      IoMapping::new(unsafe { Unique::new_unchecked(address as *mut T) }, start, page_count)
+    })
+  }
+
+  // Like ioremap, but for len consecutive Ts, such as a framebuffer.
+  pub fn ioremap_slice<T>(&mut self, physical: PhysicalAddress, len: usize, memory_type: MemoryType) -> Option<IoMapping<[T]>> {
+    self.ioremap_bytes(physical, len * mem::size_of::<T>(), memory_type).map(|(address, start, page_count)| {
+      let slice = unsafe { slice::from_raw_parts_mut(address as *mut T, len) };
+      IoMapping::new(Unique::from(slice), start, page_count)
+    })
+  }
+
+  fn ioremap_bytes(&mut self, physical: PhysicalAddress, size: usize, memory_type: MemoryType) -> Option<(VirtualAddress, VirtualPage, usize)> {
+    let &mut MemoryController { ref mut active_table, ref mut allocator, ref mut io_remapper, .. } = self;
+    io_remapper.map(active_table, allocator, physical, size, memory_type.flags())
+  }
+
+  fn iounmap(&mut self, start: VirtualPage, page_count: usize) {
+    let &mut MemoryController { ref mut active_table, ref mut allocator, ref mut io_remapper, .. } = self;
+    io_remapper.unmap(active_table, allocator, start, page_count);
+  }
+
+  pub fn alloc_contiguous(&mut self, order: usize) -> Option<PhysicalPage> {
+    self.allocator.allocate_order(order)
+  }
+
+  pub fn free_contiguous(&mut self, page: PhysicalPage, order: usize) {
+    self.allocator.deallocate_order(page, order)
   }
 }
@@ -0,0 +1,189 @@
+use core::fmt;
+
+use memory::PhysicalPage;
+use super::entry::{EntryFlags, MemoryType};
+use super::table::{Table, Level4};
+use super::{PhysicalAddress, VirtualAddress, ENTRY_COUNT, RECURSIVE_INDEX};
+
+const SIZE_4K: u64 = 4096;
+const SIZE_2M: u64 = SIZE_4K * ENTRY_COUNT as u64;
+const SIZE_1G: u64 = SIZE_2M * ENTRY_COUNT as u64;
+
+// The accessed and dirty bits are set by the CPU, so would stop otherwise identical ranges from
+// being merged.
+fn significant_flags(flags: EntryFlags) -> EntryFlags {
+  flags - EntryFlags::ACCESSED - EntryFlags::DIRTY
+}
+
+// A page is only writable or user accessible if every entry on the way to it allows that, and is
+// not executable if any of them forbids it.
+pub fn effective_flags(parent: EntryFlags, entry: EntryFlags) -> EntryFlags {
+  let mut flags = entry;
+  if !parent.contains(EntryFlags::WRITABLE) {
+    flags.remove(EntryFlags::WRITABLE);
+  }
+  if !parent.contains(EntryFlags::USER_ACCESSIBLE) {
+    flags.remove(EntryFlags::USER_ACCESSIBLE);
+  }
+  if parent.contains(EntryFlags::NO_EXECUTE) {
+    flags.insert(EntryFlags::NO_EXECUTE);
+  }
+  flags
+}
+
+fn virtual_address(p4_index: usize, p3_index: usize, p2_index: usize, p1_index: usize) -> VirtualAddress {
+  let address = ((p4_index << 39) | (p3_index << 30) | (p2_index << 21) | (p1_index << 12)) as u64;
+  if p4_index >= ENTRY_COUNT / 2 {
+    address | 0xffff_0000_0000_0000 // sign extension
+  }
+  else {
+    address
+  }
+}
+
+// A run of virtual memory mapped to contiguous physical memory by pages of the same size and flags.
+#[derive(Clone, Copy)]
+struct MappedRange {
+  start: VirtualAddress,
+  end: VirtualAddress,
+  physical_start: PhysicalAddress,
+  page_size: u64,
+  flags: EntryFlags
+}
+
+impl MappedRange {
+  fn new(start: VirtualAddress, physical_page: PhysicalPage, page_size: u64, flags: EntryFlags) -> MappedRange {
+    MappedRange { start, end: start + page_size, physical_start: physical_page.start_address(), page_size, flags: significant_flags(flags) }
+  }
+
+  fn try_merge(&mut self, next: &MappedRange) -> bool {
+    let mergeable = self.end == next.start
+                    && self.physical_start + (self.end - self.start) == next.physical_start
+                    && self.page_size == next.page_size
+                    && self.flags == next.flags;
+    if mergeable {
+      self.end = next.end;
+    }
+    mergeable
+  }
+}
+
+impl fmt::Display for MappedRange {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let size = self.end - self.start;
+    let memory_type = match MemoryType::from_flags(self.flags, self.page_size != SIZE_4K) {
+      MemoryType::WriteBack => "WB",
+      MemoryType::WriteThrough => "WT",
+      MemoryType::UncachedMinus => "UC-",
+      MemoryType::Uncached => "UC",
+      MemoryType::WriteCombining => "WC"
+    };
+    write!(f, "{:#018x}-{:#018x} -> {:#x} {:>7} KiB  {} {} {} {} {} {}",
+           self.start, self.end - 1, self.physical_start, size / 1024,
+           if self.flags.contains(EntryFlags::WRITABLE) { "W" } else { "-" },
+           if self.flags.contains(EntryFlags::NO_EXECUTE) { "NX" } else { "--" },
+           if self.flags.contains(EntryFlags::USER_ACCESSIBLE) { "U" } else { "-" },
+           if self.flags.contains(EntryFlags::GLOBAL) { "G" } else { "-" },
+           match self.page_size { SIZE_1G => "1G", SIZE_2M => "2M", _ => "4K" }, memory_type)
+  }
+}
+
+// Calls f with every mapped page in address order, apart from the page tables themselves.
+fn for_each_page<F>(p4: &Table<Level4>, mut f: F) where F: FnMut(MappedRange) {
+  let top_level = EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE;
+  for p4_index in 0..ENTRY_COUNT {
+    let p3 = match p4.next_table(p4_index) {
+      Some(p3) if p4_index != RECURSIVE_INDEX => p3,
+      _ => continue
+    };
+    let p4_flags = effective_flags(top_level, p4[p4_index].flags());
+    for p3_index in 0..ENTRY_COUNT {
+      let p3_entry = &p3[p3_index];
+      let p3_flags = effective_flags(p4_flags, p3_entry.flags());
+      if let Some(physical_page) = p3_entry.pointed_physical_page() {
+        if p3_flags.contains(EntryFlags::HUGE_PAGE) {
+          f(MappedRange::new(virtual_address(p4_index, p3_index, 0, 0), physical_page, SIZE_1G, p3_flags));
+          continue;
+        }
+      }
+      let p2 = match p3.next_table(p3_index) {
+        Some(p2) => p2,
+        None => continue
+      };
+      for p2_index in 0..ENTRY_COUNT {
+        let p2_entry = &p2[p2_index];
+        let p2_flags = effective_flags(p3_flags, p2_entry.flags());
+        if let Some(physical_page) = p2_entry.pointed_physical_page() {
+          if p2_flags.contains(EntryFlags::HUGE_PAGE) {
+            f(MappedRange::new(virtual_address(p4_index, p3_index, p2_index, 0), physical_page, SIZE_2M, p2_flags));
+            continue;
+          }
+        }
+        let p1 = match p2.next_table(p2_index) {
+          Some(p1) => p1,
+          None => continue
+        };
+        for p1_index in 0..ENTRY_COUNT {
+          let p1_entry = &p1[p1_index];
+          if let Some(physical_page) = p1_entry.pointed_physical_page() {
+            let flags = effective_flags(p2_flags, p1_entry.flags());
+            f(MappedRange::new(virtual_address(p4_index, p3_index, p2_index, p1_index), physical_page, SIZE_4K, flags));
+          }
+        }
+      }
+    }
+  }
+}
+
+// Like for_each_page, but merges neighbouring pages into ranges where possible.
+fn for_each_range<F>(p4: &Table<Level4>, mut f: F) where F: FnMut(MappedRange) {
+  let mut current: Option<MappedRange> = None;
+  for_each_page(p4, |page| {
+    if let Some(ref mut range) = current {
+      if range.try_merge(&page) {
+        return;
+      }
+      f(*range);
+    }
+    current = Some(page);
+  });
+  if let Some(range) = current {
+    f(range);
+  }
+}
+
+// Prints every mapping in the table, with the flags that apply once all levels are taken into
+// account.
+pub fn dump(p4: &Table<Level4>) {
+  println!("virtual                                  physical");
+  for_each_range(p4, |range| println!("{}", range));
+}
+
+// Checks the table for mappings which should never exist, printing each problem found. Returns
+// whether the table passed. p4_page is the physical page the table is expected to live in.
+pub fn verify(p4: &Table<Level4>, p4_page: &PhysicalPage) -> bool {
+  let mut problems = 0;
+
+  let recursive_entry = &p4[RECURSIVE_INDEX];
+  if recursive_entry.pointed_physical_page().as_ref() != Some(p4_page) {
+    println!("page table: recursive entry {} does not point at the P4 table", RECURSIVE_INDEX);
+    problems += 1;
+  }
+  if !recursive_entry.flags().contains(EntryFlags::WRITABLE) || recursive_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+    println!("page table: recursive entry {} has flags {:?}", RECURSIVE_INDEX, recursive_entry.flags());
+    problems += 1;
+  }
+
+  for_each_range(p4, |range| {
+    if range.flags.contains(EntryFlags::WRITABLE) && !range.flags.contains(EntryFlags::NO_EXECUTE) {
+      println!("page table: {:#x}-{:#x} is both writable and executable", range.start, range.end - 1);
+      problems += 1;
+    }
+    if range.physical_start % range.page_size != 0 {
+      println!("page table: huge page at {:#x} maps unaligned physical address {:#x}", range.start, range.physical_start);
+      problems += 1;
+    }
+  });
+
+  problems == 0
+}
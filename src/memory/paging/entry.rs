@@ -12,7 +12,11 @@ bitflags! {
     const ACCESSED =        1 << 5;
     const DIRTY =           1 << 6;
     const HUGE_PAGE =       1 << 7;
+    // In P1 entries, bit 7 selects the upper half of the PAT instead of making a huge page.
+    const PAT =             1 << 7;
     const GLOBAL =          1 << 8;
+    // Ignored by the CPU. Set on pages that are shared read-only, and copied on the first write.
+    const COPY_ON_WRITE =   1 << 9;
     const NO_EXECUTE =      1 << 63;
   }
 }
@@ -33,6 +37,58 @@ impl EntryFlags {
   }
 }
 
+// The memory types that the PAT is programmed with at boot, see pat_value. WRITE_THROUGH, NO_CACHE
+// and PAT select one of its 8 entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+  WriteBack,
+  WriteThrough,
+  // Uncached, but can be overridden to write-combining by the MTRRs.
+  UncachedMinus,
+  Uncached,
+  WriteCombining
+}
+
+impl MemoryType {
+  // The entry flags for this memory type. As it needs the PAT bit, write-combining is only
+  // available for 4 KiB pages.
+  pub fn flags(&self) -> EntryFlags {
+    match *self {
+      MemoryType::WriteBack => EntryFlags::empty(),
+      MemoryType::WriteThrough => EntryFlags::WRITE_THROUGH,
+      MemoryType::UncachedMinus => EntryFlags::NO_CACHE,
+      MemoryType::Uncached => EntryFlags::NO_CACHE | EntryFlags::WRITE_THROUGH,
+      MemoryType::WriteCombining => EntryFlags::PAT
+    }
+  }
+
+  // The memory type that an entry's flags select. Bit 7 is only the PAT bit in P1 entries, and is
+  // the huge page bit in the others, whose own PAT bit is never set.
+  pub fn from_flags(flags: EntryFlags, huge_page: bool) -> MemoryType {
+    let pat = !huge_page && flags.contains(EntryFlags::PAT);
+    match (pat, flags.contains(EntryFlags::NO_CACHE), flags.contains(EntryFlags::WRITE_THROUGH)) {
+      (false, false, false) => MemoryType::WriteBack,
+      (true, false, false) => MemoryType::WriteCombining,
+      (_, false, true) => MemoryType::WriteThrough,
+      (_, true, false) => MemoryType::UncachedMinus,
+      (_, true, true) => MemoryType::Uncached
+    }
+  }
+}
+
+// The value for the IA32_PAT MSR. The first four entries keep their power-on defaults, so that
+// WRITE_THROUGH and NO_CACHE on their own mean the same as they would without a PAT, and entry 4
+// is used for write-combining.
+pub fn pat_value() -> u64 {
+  const WB: u64 = 0x06;
+  const WT: u64 = 0x04;
+  const UC_MINUS: u64 = 0x07;
+  const UC: u64 = 0x00;
+  const WC: u64 = 0x01;
+  let entries = [WB, WT, UC_MINUS, UC, WC, WT, UC_MINUS, UC];
+  entries.iter().enumerate().fold(0, |value, (i, entry)| value | entry << (i * 8))
+}
+
 pub struct Entry(u64);
 
 impl Entry {
@@ -1,12 +1,59 @@
+use core::fmt;
 use core::ptr::Unique;
 
-use x86_64;
-use x86_64::instructions::tlb;
+use cpu;
+use memory::{PhysicalPage, PAGE_SIZE, Allocator, ContiguousAllocator};
+#[cfg(feature = "physical_memory_map")]
+use memory::shared_pages::SharedPages;
+use super::dump;
+use super::entry::{EntryFlags, MemoryType};
+use super::table::{recursive_next_table_address, Table, Level4, P4};
+use super::tlb;
+use super::{PhysicalAddress, VirtualAddress, VirtualPage, ENTRY_COUNT, RECURSIVE_INDEX, HUGE_2M_ORDER, HUGE_1G_ORDER};
 
-use memory::{PhysicalPage, PAGE_SIZE, Allocator};
-use super::entry::EntryFlags;
-use super::table::{Table, Level4, P4};
-use super::{PhysicalAddress, VirtualAddress, VirtualPage, ENTRY_COUNT};
+// Kernel mappings are the same in every address space, so are made global to keep them in the
+// TLB across switches. This has no effect unless global pages are enabled.
+fn leaf_flags(page: VirtualPage, flags: EntryFlags) -> EntryFlags {
+  if page.is_kernel() {
+    flags | EntryFlags::PRESENT | EntryFlags::GLOBAL
+  }
+  else {
+    flags | EntryFlags::PRESENT
+  }
+}
+
+// The outcome of walking the page table hierarchy for a single page.
+#[derive(Debug)]
+pub enum PageWalk {
+  // The entry at this level (4 for the P4 table down to 1 for a P1 table) was not present.
+  NotPresent { level: u8, index: usize },
+  // The page is mapped by an entry at this level, which is above 1 for huge pages. flags are those
+  // of that entry, effective_flags what they amount to once the tables above it are taken into
+  // account.
+  Mapped { level: u8, flags: EntryFlags, effective_flags: EntryFlags }
+}
+
+impl fmt::Display for PageWalk {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      PageWalk::NotPresent { level, index } => write!(f, "P{} entry {} is not present", level, index),
+      // Bit 7 means something different in P1 entries, so isn't left to EntryFlags' Debug to name.
+      PageWalk::Mapped { level, flags, .. } => write!(f, "mapped by P{} entry with flags {:?}{}, {:?} memory", level, flags - EntryFlags::HUGE_PAGE,
+                                                 if level > 1 { " | HUGE_PAGE" } else { "" }, MemoryType::from_flags(flags, level > 1))
+    }
+  }
+}
+
+// Where the table at this level (3 for the P3 table down to 1 for the P1 table) on the way to page
+// appears through the recursive entry.
+fn recursive_table_address(page: VirtualPage, level: u8) -> VirtualAddress {
+  let indices = [page.p4_index(), page.p3_index(), page.p2_index()];
+  let mut address = P4 as usize;
+  for &index in &indices[..4 - level as usize] {
+    address = recursive_next_table_address(address, index);
+  }
+  address as VirtualAddress
+}
 
 pub struct Mapper {
   p4: Unique<Table<Level4>>
@@ -17,7 +64,12 @@ impl Mapper {
     Mapper { p4: Unique::new_unchecked(P4) }
   }
 
-  fn p4(&self) -> &Table<Level4> {
+  // A mapper for the P4 table at the given address, which need not be the active one.
+  pub unsafe fn at(p4: *mut Table<Level4>) -> Mapper {
+    Mapper { p4: Unique::new_unchecked(p4) }
+  }
+
+  pub fn p4(&self) -> &Table<Level4> {
     unsafe { self.p4.as_ref() }
   }
 
@@ -53,6 +105,48 @@ impl Mapper {
       })
   }
 
+  pub fn walk(&self, page: VirtualPage) -> PageWalk {
+    let p4_entry = &self.p4()[page.p4_index()];
+    if !p4_entry.flags().contains(EntryFlags::PRESENT) {
+      return PageWalk::NotPresent { level: 4, index: page.p4_index() };
+    }
+    let p3 = self.p4().next_table(page.p4_index()).unwrap();
+    let p3_entry = &p3[page.p3_index()];
+    if !p3_entry.flags().contains(EntryFlags::PRESENT) {
+      return PageWalk::NotPresent { level: 3, index: page.p3_index() };
+    }
+    let p3_flags = dump::effective_flags(p4_entry.flags(), p3_entry.flags());
+    if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+      return PageWalk::Mapped { level: 3, flags: p3_entry.flags(), effective_flags: p3_flags };
+    }
+    let p2 = p3.next_table(page.p3_index()).unwrap();
+    let p2_entry = &p2[page.p2_index()];
+    if !p2_entry.flags().contains(EntryFlags::PRESENT) {
+      return PageWalk::NotPresent { level: 2, index: page.p2_index() };
+    }
+    let p2_flags = dump::effective_flags(p3_flags, p2_entry.flags());
+    if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+      return PageWalk::Mapped { level: 2, flags: p2_entry.flags(), effective_flags: p2_flags };
+    }
+    let p1 = p2.next_table(page.p2_index()).unwrap();
+    let p1_entry = &p1[page.p1_index()];
+    if !p1_entry.flags().contains(EntryFlags::PRESENT) {
+      return PageWalk::NotPresent { level: 1, index: page.p1_index() };
+    }
+    PageWalk::Mapped { level: 1, flags: p1_entry.flags(), effective_flags: dump::effective_flags(p2_flags, p1_entry.flags()) }
+  }
+
+  // Prints compacted ranges of every mapping, for debugging.
+  pub fn dump(&self) {
+    dump::dump(self.p4());
+  }
+
+  // Checks invariants that every page table should hold, printing any problems found. p4_page is
+  // the physical page that the P4 table is expected to live in.
+  pub fn verify(&self, p4_page: &PhysicalPage) -> bool {
+    dump::verify(self.p4(), p4_page)
+  }
+
   pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
     let offset = virtual_address % PAGE_SIZE;
     self.translate_page(VirtualPage::containing_address(virtual_address))
@@ -60,11 +154,31 @@ impl Mapper {
   }
 
   pub fn map_to<A>(&mut self, virtual_page: VirtualPage, physical_page: PhysicalPage, flags: EntryFlags, allocator: &mut A) where A: Allocator {
-    let mut p3 = self.p4_mut().next_table_create(virtual_page.p4_index(), allocator);
-    let mut p2 = p3.next_table_create(virtual_page.p3_index(), allocator);
-    let mut p1 = p2.next_table_create(virtual_page.p2_index(), allocator);
+    let mut p3 = self.p4_mut().next_table_create(virtual_page.p4_index(), flags, allocator);
+    let mut p2 = p3.next_table_create(virtual_page.p3_index(), flags, allocator);
+    let mut p1 = p2.next_table_create(virtual_page.p2_index(), flags, allocator);
     assert!(p1[virtual_page.p1_index()].is_unused());
-    p1[virtual_page.p1_index()].set(physical_page, flags | EntryFlags::PRESENT);
+    p1[virtual_page.p1_index()].set(physical_page, leaf_flags(virtual_page, flags));
+  }
+
+  // Creates the page tables needed to map every page from start to end inclusive with the given
+  // flags, without mapping any of them.
+  pub fn create_tables<A>(&mut self, start: VirtualPage, end: VirtualPage, flags: EntryFlags, allocator: &mut A) where A: Allocator {
+    for page in VirtualPage::range_inclusive(start, end) {
+      self.p4_mut().next_table_create(page.p4_index(), flags, allocator)
+                   .next_table_create(page.p3_index(), flags, allocator)
+                   .next_table_create(page.p2_index(), flags, allocator);
+    }
+  }
+
+  // Creates the P3 table of every kernel P4 entry up front. Address spaces share these tables, so
+  // kernel mappings made later show up in all of them. They are never freed.
+  pub fn create_kernel_tables<A>(&mut self, allocator: &mut A) where A: Allocator {
+    for p4_index in ENTRY_COUNT / 2..ENTRY_COUNT {
+      if p4_index != RECURSIVE_INDEX {
+        self.p4_mut().next_table_create(p4_index, EntryFlags::empty(), allocator);
+      }
+    }
   }
 
   pub fn map<A>(&mut self, virtual_page: VirtualPage, flags: EntryFlags, allocator: &mut A) where A: Allocator {
@@ -77,17 +191,205 @@ impl Mapper {
     self.map_to(virtual_page, physical_page, flags, allocator);
   }
 
+  pub fn map_huge_2m_to<A>(&mut self, virtual_page: VirtualPage, physical_page: PhysicalPage, flags: EntryFlags, allocator: &mut A) where A: Allocator {
+    assert!(virtual_page.number % ENTRY_COUNT == 0, "virtual address {:#x} is not 2 MiB aligned", virtual_page.start_address());
+    assert!(physical_page.number % ENTRY_COUNT == 0, "physical address {:#x} is not 2 MiB aligned", physical_page.start_address());
+    assert!(!flags.contains(EntryFlags::PAT), "huge pages can't be write-combining, as bit 7 is the huge page bit");
+    let mut p3 = self.p4_mut().next_table_create(virtual_page.p4_index(), flags, allocator);
+    let mut p2 = p3.next_table_create(virtual_page.p3_index(), flags, allocator);
+    assert!(p2[virtual_page.p2_index()].is_unused());
+    p2[virtual_page.p2_index()].set(physical_page, leaf_flags(virtual_page, flags) | EntryFlags::HUGE_PAGE);
+  }
+
+  pub fn map_huge_2m<A>(&mut self, virtual_page: VirtualPage, flags: EntryFlags, allocator: &mut A) where A: ContiguousAllocator {
+    let physical_page = allocator.allocate_order(HUGE_2M_ORDER).expect("out of memory");
+    self.map_huge_2m_to(virtual_page, physical_page, flags, allocator);
+  }
+
+  pub fn map_huge_1g_to<A>(&mut self, virtual_page: VirtualPage, physical_page: PhysicalPage, flags: EntryFlags, allocator: &mut A) where A: Allocator {
+    assert!(cpu::has_1gib_pages(), "1 GiB pages are not supported by this CPU");
+    assert!(virtual_page.number % (ENTRY_COUNT * ENTRY_COUNT) == 0, "virtual address {:#x} is not 1 GiB aligned", virtual_page.start_address());
+    assert!(physical_page.number % (ENTRY_COUNT * ENTRY_COUNT) == 0, "physical address {:#x} is not 1 GiB aligned", physical_page.start_address());
+    assert!(!flags.contains(EntryFlags::PAT), "huge pages can't be write-combining, as bit 7 is the huge page bit");
+    let mut p3 = self.p4_mut().next_table_create(virtual_page.p4_index(), flags, allocator);
+    assert!(p3[virtual_page.p3_index()].is_unused());
+    p3[virtual_page.p3_index()].set(physical_page, leaf_flags(virtual_page, flags) | EntryFlags::HUGE_PAGE);
+  }
+
+  pub fn map_huge_1g<A>(&mut self, virtual_page: VirtualPage, flags: EntryFlags, allocator: &mut A) where A: ContiguousAllocator {
+    let physical_page = allocator.allocate_order(HUGE_1G_ORDER).expect("out of memory");
+    self.map_huge_1g_to(virtual_page, physical_page, flags, allocator);
+  }
+
+  // Points an existing 4 KiB mapping at a different physical page, or changes its flags.
+  pub fn remap(&mut self, page: VirtualPage, physical_page: PhysicalPage, flags: EntryFlags) {
+    {
+      let p1 = self.p4_mut()
+                   .next_table_mut(page.p4_index())
+                   .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                   .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                   .expect("page is not mapped by a P1 table");
+      assert!(!p1[page.p1_index()].is_unused(), "page is not mapped");
+      p1[page.p1_index()].set(physical_page, leaf_flags(page, flags));
+    }
+    tlb::flush(page.start_address());
+  }
+
   pub fn unmap<A>(&mut self, page: VirtualPage, allocator: &mut A) where A: Allocator {
-    assert!(self.translate(page.start_address()).is_some());
-    let p1 = self.p4_mut()
-                 .next_table_mut(page.p4_index())
-                 .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                 .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                 .expect("huge pages are not supported");
-    let physical_page = p1[page.p1_index()].pointed_physical_page().unwrap();
-    p1[page.p1_index()].set_unused();
-    tlb::flush(x86_64::VirtAddr::new(page.start_address()));
-    // TODO free up p1/2/3 tables if not used any more
+    let physical_page = self.unmap_without_free(page, allocator);
     allocator.deallocate(physical_page);
   }
+
+  // Unmaps the page but leaves the physical page it mapped alone, for pages that the allocator
+  // does not own. Page tables that become empty are still freed.
+  pub fn unmap_without_free<A>(&mut self, page: VirtualPage, allocator: &mut A) -> PhysicalPage where A: Allocator {
+    let physical_page = self.clear_entry(page, allocator);
+    tlb::flush(page.start_address());
+    physical_page
+  }
+
+  // Unmaps and frees every mapped page from start to end inclusive, skipping pages that aren't
+  // mapped. The TLB is flushed once at the end rather than once per page.
+  pub fn unmap_range<A>(&mut self, start: VirtualPage, end: VirtualPage, allocator: &mut A) where A: Allocator {
+    for page in VirtualPage::range_inclusive(start, end) {
+      if self.translate_page(page).is_some() {
+        let physical_page = self.clear_entry(page, allocator);
+        allocator.deallocate(physical_page);
+      }
+    }
+    tlb::flush_range(start, end);
+  }
+
+  // Clears the P1 entry mapping the page and frees any page tables left empty, without flushing
+  // the page's TLB entry.
+  fn clear_entry<A>(&mut self, page: VirtualPage, allocator: &mut A) -> PhysicalPage where A: Allocator {
+    assert!(self.translate(page.start_address()).is_some());
+    let physical_page = {
+      let p1 = self.p4_mut()
+                   .next_table_mut(page.p4_index())
+                   .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                   .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                   .expect("page is part of a huge page");
+      let physical_page = p1[page.p1_index()].pointed_physical_page().unwrap();
+      p1[page.p1_index()].set_unused();
+      physical_page
+    };
+    self.free_empty_tables(page, allocator);
+    physical_page
+  }
+
+  pub fn unmap_huge_2m<A>(&mut self, page: VirtualPage, allocator: &mut A) where A: ContiguousAllocator {
+    let physical_page = {
+      let p2 = self.p4_mut()
+                   .next_table_mut(page.p4_index())
+                   .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                   .expect("page is not mapped as a 2 MiB page");
+      assert!(p2[page.p2_index()].flags().contains(EntryFlags::HUGE_PAGE), "page is not mapped as a 2 MiB page");
+      let physical_page = p2[page.p2_index()].pointed_physical_page().unwrap();
+      p2[page.p2_index()].set_unused();
+      physical_page
+    };
+    tlb::flush(page.start_address());
+    if !page.is_kernel() {
+      let p2_freed = {
+        let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+        p3.free_next_table_if_empty(page.p3_index(), recursive_table_address(page, 2), allocator)
+      };
+      if p2_freed {
+        self.p4_mut().free_next_table_if_empty(page.p4_index(), recursive_table_address(page, 3), allocator);
+      }
+    }
+    allocator.deallocate_order(physical_page, HUGE_2M_ORDER);
+  }
+
+  pub fn unmap_huge_1g<A>(&mut self, page: VirtualPage, allocator: &mut A) where A: ContiguousAllocator {
+    let physical_page = {
+      let p3 = self.p4_mut().next_table_mut(page.p4_index()).expect("page is not mapped as a 1 GiB page");
+      assert!(p3[page.p3_index()].flags().contains(EntryFlags::HUGE_PAGE), "page is not mapped as a 1 GiB page");
+      let physical_page = p3[page.p3_index()].pointed_physical_page().unwrap();
+      p3[page.p3_index()].set_unused();
+      physical_page
+    };
+    tlb::flush(page.start_address());
+    if !page.is_kernel() {
+      self.p4_mut().free_next_table_if_empty(page.p4_index(), recursive_table_address(page, 3), allocator);
+    }
+    allocator.deallocate_order(physical_page, HUGE_1G_ORDER);
+  }
+
+  // Shares every page in the lower half with target, which must have nothing mapped there yet.
+  // Writable pages become read-only and copy-on-write in both tables. The kernel half is linked
+  // into target as it is, which keeps later kernel mappings visible in both, as every kernel P4
+  // entry is created at boot. The caller must flush the TLB afterwards if this table is active.
+  #[cfg(feature = "physical_memory_map")]
+  pub fn share_into<A>(&mut self, target: &mut Mapper, shared_pages: &mut SharedPages, allocator: &mut A) where A: Allocator {
+    for p4_index in 0..ENTRY_COUNT / 2 {
+      let p4_flags = self.p4()[p4_index].flags();
+      let p3 = match self.p4_mut().next_table_mut(p4_index) {
+        Some(p3) => p3,
+        None => continue
+      };
+      let target_p3 = target.p4_mut().next_table_create(p4_index, p4_flags, allocator);
+      for p3_index in 0..ENTRY_COUNT {
+        assert!(!p3[p3_index].flags().contains(EntryFlags::HUGE_PAGE), "huge pages cannot be shared");
+        let p3_flags = p3[p3_index].flags();
+        let p2 = match p3.next_table_mut(p3_index) {
+          Some(p2) => p2,
+          None => continue
+        };
+        let target_p2 = target_p3.next_table_create(p3_index, p3_flags, allocator);
+        for p2_index in 0..ENTRY_COUNT {
+          assert!(!p2[p2_index].flags().contains(EntryFlags::HUGE_PAGE), "huge pages cannot be shared");
+          let p2_flags = p2[p2_index].flags();
+          let p1 = match p2.next_table_mut(p2_index) {
+            Some(p1) => p1,
+            None => continue
+          };
+          let target_p1 = target_p2.next_table_create(p2_index, p2_flags, allocator);
+          for p1_index in 0..ENTRY_COUNT {
+            let physical_page = match p1[p1_index].pointed_physical_page() {
+              Some(physical_page) => physical_page,
+              None => continue
+            };
+            let mut flags = p1[p1_index].flags();
+            if flags.contains(EntryFlags::WRITABLE) {
+              flags = (flags - EntryFlags::WRITABLE) | EntryFlags::COPY_ON_WRITE;
+              p1[p1_index].set(physical_page.clone(), flags);
+            }
+            shared_pages.share(&physical_page);
+            target_p1[p1_index].set(physical_page, flags);
+          }
+        }
+      }
+    }
+
+    for p4_index in ENTRY_COUNT / 2..ENTRY_COUNT {
+      if p4_index == RECURSIVE_INDEX {
+        continue;
+      }
+      if let Some(physical_page) = self.p4()[p4_index].pointed_physical_page() {
+        target.p4_mut()[p4_index].set(physical_page, self.p4()[p4_index].flags());
+      }
+    }
+  }
+
+  // Walks back up from the P1 table that mapped the page, freeing each table that has become empty.
+  // Kernel tables are linked into every address space, and may still be cached under other PCIDs,
+  // so they are kept even when empty.
+  fn free_empty_tables<A>(&mut self, page: VirtualPage, allocator: &mut A) where A: Allocator {
+    if page.is_kernel() {
+      return;
+    }
+    let p2_freed = {
+      let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+      let p1_freed = {
+        let p2 = p3.next_table_mut(page.p3_index()).unwrap();
+        p2.free_next_table_if_empty(page.p2_index(), recursive_table_address(page, 1), allocator)
+      };
+      p1_freed && p3.free_next_table_if_empty(page.p3_index(), recursive_table_address(page, 2), allocator)
+    };
+    if p2_freed {
+      self.p4_mut().free_next_table_if_empty(page.p4_index(), recursive_table_address(page, 3), allocator);
+    }
+  }
 }
@@ -1,22 +1,85 @@
+mod dump;
 mod entry;
 mod mapper;
 mod table;
 mod temporary;
+mod tlb;
 
-use core::ops::{Deref, DerefMut, Add};
+use core::ops::{Deref, DerefMut, Add, Sub};
+use core::cmp;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use multiboot2::BootInformation;
-use x86_64;
-use x86_64::instructions::tlb;
-use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::PhysFrame;
+use multiboot2::{BootInformation, MemoryAreaIter};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::Msr;
+
+use cpu;
 
 use memory::{PAGE_SIZE, Allocator, PhysicalPage};
-pub use self::entry::EntryFlags;
+use memory::area_allocator::MAX_PHYSICAL_MEMORY;
+use memory::stack_allocator::register_guard_page;
+use {KERNEL_OFFSET, PHYSICAL_MEMORY_OFFSET};
+pub use self::entry::{EntryFlags, MemoryType};
 use self::mapper::Mapper;
-use self::temporary::TemporaryPage;
+pub use self::mapper::PageWalk;
+use self::table::{Table, Level4, Level2, Level1};
+pub use self::temporary::TemporaryPage;
+pub use self::tlb::{init as init_tlb, flush_address_space, global_pages_enabled, pcid_enabled};
 
 const ENTRY_COUNT: usize = 512;
+const IA32_PAT: u32 = 0x277;
+
+// The P4 entry that maps the page tables themselves. This can't be the last entry, as the kernel
+// is mapped there.
+const RECURSIVE_INDEX: usize = 510;
+
+// The number of physical pages currently used for page tables, not counting the boot tables.
+static PAGE_TABLE_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn page_table_pages() -> usize {
+  PAGE_TABLE_PAGES.load(Ordering::Relaxed)
+}
+
+// PCIDs are 12 bits, and 0 is left for the boot page table.
+const PCID_COUNT: usize = 4096;
+static NEXT_PCID: AtomicUsize = AtomicUsize::new(1);
+// The P4 page number of the table that last used each PCID, whose mappings may still be cached.
+static PCID_OWNERS: Mutex<[usize; PCID_COUNT]> = Mutex::new([0; PCID_COUNT]);
+
+// Recorded as the owner of a PCID whose cached mappings can't be trusted by any table.
+const NO_OWNER: usize = usize::max_value();
+
+// PCIDs are handed out in turn and may end up shared once they wrap around, which only costs a
+// flush when switching between the tables sharing one. A PCID handed out again may still have the
+// mappings of a freed table cached, whose P4 page the new table could be reusing, so it is always
+// flushed the first time it is switched to.
+fn allocate_pcid() -> u16 {
+  if !tlb::pcid_enabled() {
+    return 0;
+  }
+  let pcid = NEXT_PCID.fetch_add(1, Ordering::Relaxed) % (PCID_COUNT - 1) + 1;
+  interrupts::without_interrupts(|| PCID_OWNERS.lock()[pcid] = NO_OWNER);
+  pcid as u16
+}
+
+static PHYSICAL_MEMORY_MAPPED: AtomicBool = AtomicBool::new(false);
+
+// Where a page of RAM can be accessed directly, once map_physical_memory has been called. Until
+// then, page tables can only be reached through the recursive mapping.
+pub fn physical_to_virtual(address: PhysicalAddress) -> Option<VirtualAddress> {
+  if PHYSICAL_MEMORY_MAPPED.load(Ordering::Relaxed) && address < MAX_PHYSICAL_MEMORY {
+    Some(address + PHYSICAL_MEMORY_OFFSET)
+  }
+  else {
+    None
+  }
+}
+
+// Buddy allocator orders of the physically contiguous memory backing 2 MiB and 1 GiB pages.
+const HUGE_2M_ORDER: usize = 9;
+const HUGE_1G_ORDER: usize = 18;
 
 pub type PhysicalAddress = u64;
 pub type VirtualAddress = u64;
@@ -26,6 +89,36 @@ pub struct VirtualPage {
   number: usize
 }
 
+// The kernel is linked at KERNEL_OFFSET above where it is loaded, and its sections, the VGA buffer
+// and the multiboot information are all mapped at that offset from their physical addresses. The
+// boot code is the exception, as it is linked at its physical address so that it can run before
+// paging is enabled.
+pub fn kernel_physical_address(address: VirtualAddress) -> PhysicalAddress {
+  if address >= KERNEL_OFFSET { address - KERNEL_OFFSET } else { address }
+}
+
+pub fn kernel_virtual_address(address: PhysicalAddress) -> VirtualAddress {
+  address + KERNEL_OFFSET
+}
+
+// Programs the PAT with the memory types in MemoryType. Every x86_64 CPU has a PAT.
+// Follows the sequence the SDM gives for changing memory types, so that no cache line or TLB entry
+// is left holding the memory type a page had under the old PAT.
+pub fn init_pat() {
+  interrupts::without_interrupts(|| {
+    let cr0 = Cr0::read();
+    unsafe {
+      Cr0::write((cr0 | Cr0Flags::CACHE_DISABLE) - Cr0Flags::NOT_WRITE_THROUGH);
+      cpu::write_back_caches();
+      tlb::flush_global();
+      Msr::new(IA32_PAT).write(entry::pat_value());
+      cpu::write_back_caches();
+      tlb::flush_global();
+      Cr0::write(cr0);
+    }
+  });
+}
+
 impl VirtualPage {
   pub fn containing_address(address: VirtualAddress) -> VirtualPage {
     assert!(address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000, "invalid address: 0x{:x}", address);
@@ -36,6 +129,11 @@ impl VirtualPage {
     self.number as u64 * PAGE_SIZE
   }
 
+  // Kernel pages are in the upper half, and are the same in every address space.
+  pub fn is_kernel(&self) -> bool {
+    self.start_address() >= 0xffff_8000_0000_0000
+  }
+
   fn p4_index(&self) -> usize {
     (self.number >> 27) & 0o777
   }
@@ -65,6 +163,14 @@ impl Add<usize> for VirtualPage {
     }
 }
 
+impl Sub<usize> for VirtualPage {
+    type Output = VirtualPage;
+
+    fn sub(self, rhs: usize) -> VirtualPage {
+        VirtualPage { number: self.number - rhs }
+    }
+}
+
 #[derive(Clone)]
 pub struct VirtualPageIter {
   start: VirtualPage,
@@ -111,32 +217,78 @@ impl ActivePageTable {
 
   pub fn with<F>(&mut self, table: &mut InactivePageTable, temporary_page: &mut TemporaryPage, f: F) where F: FnOnce(&mut Mapper) {
     {
-      // unsafe because reading CR3 throws a CPU exception if not in kernel mode. but we're a kernel!
-      let backup = PhysicalPage::containing_address(Cr3::read().0.start_address().as_u64());
+      let backup = self.p4_page();
       let p4_table = temporary_page.map_table_physical_page(backup.clone(), self);
-      self.p4_mut()[511].set(table.p4.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
-      tlb::flush_all();
+      self.p4_mut()[RECURSIVE_INDEX].set(table.p4.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+      // The recursive mapping is never global, so only this address space needs flushing.
+      tlb::flush_address_space();
       f(self);
-      p4_table[511].set(backup, EntryFlags::PRESENT | EntryFlags::WRITABLE);
-      tlb::flush_all();
+      p4_table[RECURSIVE_INDEX].set(backup, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+      tlb::flush_address_space();
     }
     temporary_page.unmap(self);
+    table.modified = true;
   }
 
-  pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
-    let (cr3_start, cr3_flags) = Cr3::read();
-    let old_table = InactivePageTable {
-      p4: PhysicalPage::containing_address(cr3_start.start_address().as_u64())
-    };
-    unsafe {
-      Cr3::write(PhysFrame::from_start_address(x86_64::PhysAddr::new(new_table.p4.start_address() as u64)).unwrap(), cr3_flags);
+  // Breaks the huge page containing the given page into pages one level down: a 1 GiB page becomes
+  // 2 MiB pages and a 2 MiB page becomes 4 KiB pages. The mapping itself does not change. The new
+  // table is filled in through the temporary page, as the huge page may be the one mapping it.
+  pub fn split_huge_page<A>(&mut self, page: VirtualPage, temporary_page: &mut TemporaryPage, allocator: &mut A) where A: Allocator {
+    let table_page = allocator.allocate().expect("out of physical pages");
+    let table_address = temporary_page.map(table_page.clone(), self);
+    {
+      let p3 = self.p4_mut().next_table_mut(page.p4_index()).expect("page is not mapped");
+      if p3[page.p3_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+        let table = unsafe { &mut *(table_address as *mut Table<Level2>) };
+        p3.split_huge_entry(page.p3_index(), table, table_page, ENTRY_COUNT);
+      }
+      else {
+        let p2 = p3.next_table_mut(page.p3_index()).expect("page is not mapped");
+        let table = unsafe { &mut *(table_address as *mut Table<Level1>) };
+        p2.split_huge_entry(page.p2_index(), table, table_page, 1);
+      }
     }
+    // A single invlpg anywhere inside the old huge page evicts its TLB entry, and any cached walk
+    // that ended at the huge page entry.
+    tlb::flush(page.start_address());
+    temporary_page.unmap(self);
+  }
+
+  pub fn p4_page(&self) -> PhysicalPage {
+    PhysicalPage::containing_address(cpu::read_cr3() & 0x000fffff_fffff000)
+  }
+
+  // With PCIDs, the TLB entries of the new table are kept if they can't have gone stale since it
+  // was last active.
+  pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
+    let old_table = InactivePageTable { p4: self.p4_page(), pcid: tlb::current_pcid(), modified: false };
+    let mut cr3 = new_table.p4.start_address() | new_table.pcid as u64;
+    // Interrupts are kept off until CR3 is written, so that nothing run from an interrupt handler
+    // can take the lock or switch tables in between.
+    interrupts::without_interrupts(|| {
+      if tlb::pcid_enabled() {
+        let mut owners = PCID_OWNERS.lock();
+        let owner = &mut owners[new_table.pcid as usize];
+        if *owner == new_table.p4.number && !new_table.modified {
+          // modified only covers the lower half. This is only safe because kernel page tables are
+          // never freed (see Mapper::free_empty_tables), so cached walks through the shared kernel
+          // half can't lead into a page that has since been reused.
+          cr3 |= tlb::CR3_NO_FLUSH;
+        }
+        *owner = new_table.p4.number;
+      }
+      unsafe { cpu::write_cr3(cr3) };
+    });
     old_table
   }
 }
 
 pub struct InactivePageTable {
-  p4: PhysicalPage
+  p4: PhysicalPage,
+  pcid: u16,
+  // Whether the table has been changed since it was last active, so that any of its mappings
+  // cached under its PCID may be stale.
+  modified: bool
 }
 
 impl InactivePageTable {
@@ -144,57 +296,177 @@ impl InactivePageTable {
     {
       let table = temporary_page.map_table_physical_page(page.clone(), active_table);
       table.zero();
-      table[511].set(page.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+      table[RECURSIVE_INDEX].set(page.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
     }
+    PAGE_TABLE_PAGES.fetch_add(1, Ordering::Relaxed);
     temporary_page.unmap(active_table);
-    InactivePageTable { p4: page }
+    InactivePageTable { p4: page, pcid: allocate_pcid(), modified: true }
+  }
+
+  // Like new, but sets the table up through the physical memory map instead of a temporary page.
+  #[cfg(feature = "physical_memory_map")]
+  pub fn new_direct(page: PhysicalPage) -> InactivePageTable {
+    {
+      let table = unsafe { &mut *(Self::table_address(&page) as *mut Table<Level4>) };
+      table.zero();
+      table[RECURSIVE_INDEX].set(page.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+    }
+    PAGE_TABLE_PAGES.fetch_add(1, Ordering::Relaxed);
+    InactivePageTable { p4: page, pcid: allocate_pcid(), modified: true }
+  }
+
+  // Gives access to the table without switching to it or flushing the whole TLB, which
+  // ActivePageTable::with has to do.
+  #[cfg(feature = "physical_memory_map")]
+  pub fn mapper(&mut self) -> Mapper {
+    self.modified = true;
+    unsafe { Mapper::at(Self::table_address(&self.p4) as *mut Table<Level4>) }
+  }
+
+  // Like Mapper::dump and Mapper::verify, but for this table, which is read through the physical
+  // memory map rather than the recursive mapping.
+  #[cfg(feature = "physical_memory_map")]
+  pub fn dump(&self) {
+    dump::dump(self.table());
+  }
+
+  #[cfg(feature = "physical_memory_map")]
+  pub fn verify(&self) -> bool {
+    dump::verify(self.table(), &self.p4)
+  }
+
+  #[cfg(feature = "physical_memory_map")]
+  fn table(&self) -> &Table<Level4> {
+    unsafe { &*(Self::table_address(&self.p4) as *const Table<Level4>) }
+  }
+
+  #[cfg(feature = "physical_memory_map")]
+  fn table_address(page: &PhysicalPage) -> VirtualAddress {
+    physical_to_virtual(page.start_address()).expect("physical memory is not mapped")
+  }
+}
+
+// Walks the currently active page table. This is for exception handlers, which have no access to
+// the ActivePageTable owned by the MemoryController.
+pub fn walk_active_table(address: VirtualAddress) -> PageWalk {
+  let mapper = unsafe { Mapper::new() };
+  mapper.walk(VirtualPage::containing_address(address))
+}
+
+// Maps a page of the active table without the ActivePageTable, for the page fault handler to back
+// heap pages with while the MemoryController may be locked. Returns false unless the page is unmapped
+// and the tables it needs already exist, as the controller may be part way through creating others.
+// The table is reached through the physical memory map where there is one, as the controller may
+// also have pointed the recursive entry at an inactive table. Without it, this fails for as long as
+// ActivePageTable::with is running, so nothing run by with may touch new heap pages.
+pub fn map_in_active_table<A>(page: VirtualPage, flags: EntryFlags, allocator: &mut A) -> bool where A: Allocator {
+  let p4_page = PhysicalPage::containing_address(cpu::read_cr3() & 0x000fffff_fffff000);
+  let mut mapper = match physical_to_virtual(p4_page.start_address()) {
+    Some(p4_address) => unsafe { Mapper::at(p4_address as *mut Table<Level4>) },
+    None => {
+      let mapper = unsafe { Mapper::new() };
+      if mapper.p4()[RECURSIVE_INDEX].pointed_physical_page() != Some(p4_page) {
+        return false;
+      }
+      mapper
+    }
+  };
+  match mapper.walk(page) {
+    PageWalk::NotPresent { level: 1, .. } => {},
+    _ => return false
   }
+  match allocator.allocate() {
+    Some(physical_page) => mapper.map_to(page, physical_page, flags, allocator),
+    None => return false
+  }
+  true
 }
 
-pub fn remap_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> ActivePageTable where A: Allocator {
-  let mut temporary_page = TemporaryPage::new(VirtualPage { number: 0xcafebabe }, allocator);
+// Also returns the temporary page used to set up the new table, for later use.
+pub fn remap_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> (ActivePageTable, TemporaryPage) where A: Allocator {
+  // The page just below the kernel is never used for anything else.
+  let temporary_page_address = KERNEL_OFFSET - PAGE_SIZE;
+  let mut temporary_page = TemporaryPage::new(VirtualPage::containing_address(temporary_page_address), allocator);
   let mut active_table = unsafe { ActivePageTable::new() };
   let mut new_table = {
     let physical_page = allocator.allocate().expect("out of memory");
     InactivePageTable::new(physical_page, &mut active_table, &mut temporary_page)
   };
+  let new_p4_page = new_table.p4.clone();
   active_table.with(&mut new_table, &mut temporary_page, |mapper| {
-    // Identity map all kernel sections
+    mapper.create_kernel_tables(allocator);
+
+    // Map all kernel sections into the higher half. The boot code is left out, as it is only
+    // needed before this table exists.
     let elf_sections_tag = boot_info.elf_sections_tag().expect("elf sections tag missing from boot info");
     for section in elf_sections_tag.sections() {
-      if !section.is_allocated() {
+      if !section.is_allocated() || section.start_address() < KERNEL_OFFSET {
         continue;
       }
       assert!(section.start_address() % PAGE_SIZE == 0, "elf section not page aligned");
       println!("remapping kernel section at addr: {:#x}, size: {:#x}", section.start_address(), section.size());
       let flags = EntryFlags::from_elf_section_flags(&section);
-      let start = PhysicalPage::containing_address(section.start_address());
-      let end = PhysicalPage::containing_address(section.end_address() - 1);
-      for page in PhysicalPage::range_inclusive(start, end) {
-        mapper.identity_map(page, flags, allocator);
-      }
+      map_kernel_range(mapper, section.start_address(), section.end_address(), flags, allocator);
     }
 
-    // Identity map the VGA buffer
     println!("remapping vga buffer");
-    let vga_buffer_page = PhysicalPage::containing_address(0xb8000);
-    mapper.identity_map(vga_buffer_page, EntryFlags::WRITABLE, allocator);
-
-    // Identity map the multiboot info structure
-    let multiboot_start = PhysicalPage::containing_address(boot_info.start_address() as u64);
-    let multiboot_end = PhysicalPage::containing_address(boot_info.end_address() as u64 - 1);
-    println!("remapping multiboot info ({:?} - {:?})", multiboot_start, multiboot_end);
-    for page in PhysicalPage::range_inclusive(multiboot_start, multiboot_end) {
-      mapper.identity_map(page, EntryFlags::PRESENT, allocator);
-    }
+    let vga_buffer = kernel_virtual_address(0xb8000);
+    map_kernel_range(mapper, vga_buffer, vga_buffer + PAGE_SIZE, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, allocator);
+
+    let multiboot_start = boot_info.start_address() as u64;
+    let multiboot_end = boot_info.end_address() as u64;
+    println!("remapping multiboot info ({:#x} - {:#x})", multiboot_start, multiboot_end);
+    map_kernel_range(mapper, multiboot_start, multiboot_end, EntryFlags::PRESENT | EntryFlags::NO_EXECUTE, allocator);
+
+    assert!(mapper.verify(&new_p4_page), "new page table failed verification");
   });
 
   let old_table = active_table.switch(new_table);
   println!("switched to new page table");
 
-  let old_p4_page = VirtualPage::containing_address(old_table.p4.start_address());
-  active_table.unmap(old_p4_page, allocator);
+  // The boot P4 is part of the kernel image, so it is unmapped without being freed.
+  let old_p4_page = VirtualPage::containing_address(kernel_virtual_address(old_table.p4.start_address()));
+  active_table.unmap_without_free(old_p4_page, allocator);
+  // The old P4 sits right below the boot stack, so an overflow of it lands here.
+  register_guard_page(old_p4_page, "boot stack");
   println!("guard page at {:#x}", old_p4_page.start_address());
 
-  active_table
+  (active_table, temporary_page)
+}
+
+// Maps every memory area at PHYSICAL_MEMORY_OFFSET, using 2 MiB pages wherever an area covers one
+// completely and 4 KiB pages for the rest, so that nothing but RAM ends up mapped as write-back
+// memory. Page tables are reached through this mapping from then on, rather than through the
+// recursive mapping. Only used with the physical_memory_map feature.
+pub fn map_physical_memory<A>(active_table: &mut ActivePageTable, memory_areas: MemoryAreaIter, allocator: &mut A) where A: Allocator {
+  let huge_page_size = PAGE_SIZE * ENTRY_COUNT as u64;
+  let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+  for area in memory_areas {
+    // Only whole pages of RAM are mapped, as with the page allocator.
+    let start = (area.start_address() + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    let end = cmp::min(area.end_address(), MAX_PHYSICAL_MEMORY) / PAGE_SIZE * PAGE_SIZE;
+    let mut address = start;
+    while address < end {
+      let page = VirtualPage::containing_address(address + PHYSICAL_MEMORY_OFFSET);
+      if address % huge_page_size == 0 && address + huge_page_size <= end {
+        active_table.map_huge_2m_to(page, PhysicalPage::containing_address(address), flags, allocator);
+        address += huge_page_size;
+      }
+      else {
+        active_table.map_to(page, PhysicalPage::containing_address(address), flags, allocator);
+        address += PAGE_SIZE;
+      }
+    }
+  }
+  PHYSICAL_MEMORY_MAPPED.store(true, Ordering::Relaxed);
+}
+
+// Maps [start, end) to the physical memory KERNEL_OFFSET below it.
+fn map_kernel_range<A>(mapper: &mut Mapper, start: VirtualAddress, end: VirtualAddress, flags: EntryFlags, allocator: &mut A) where A: Allocator {
+  let start_page = VirtualPage::containing_address(start);
+  let end_page = VirtualPage::containing_address(end - 1);
+  for page in VirtualPage::range_inclusive(start_page, end_page) {
+    let physical_page = PhysicalPage::containing_address(kernel_physical_address(page.start_address()));
+    mapper.map_to(page, physical_page, flags, allocator);
+  }
 }
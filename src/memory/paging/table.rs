@@ -1,11 +1,21 @@
 use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
+use core::sync::atomic::Ordering;
 
-use memory::Allocator;
+use memory::{Allocator, PhysicalPage};
 use memory::paging::entry::*;
-use memory::paging::ENTRY_COUNT;
+use memory::paging::{physical_to_virtual, tlb, VirtualAddress, ENTRY_COUNT, PAGE_TABLE_PAGES};
 
-pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
+// The P4 table as seen through the recursive entry, which is RECURSIVE_INDEX (510) at every level.
+pub const P4: *mut Table<Level4> = 0o177777_776_776_776_776_0000 as *mut _;
+
+// Where the table pointed to by entry index of the table at table_address appears through the
+// recursive entry. Shifting out the P4 index moves one level down, as the recursive entry is always
+// used first. The result is sign extended to keep it canonical.
+pub fn recursive_next_table_address(table_address: usize, index: usize) -> usize {
+  let address = ((table_address << 9) | (index << 12)) & 0o777_777_777_777_0000;
+  if address & (1 << 47) != 0 { address | 0xffff_0000_0000_0000 } else { address }
+}
 
 pub trait TableLevel {}
 
@@ -57,14 +67,21 @@ impl<L> Table<L> where L: TableLevel {
       entry.set_unused();
     }
   }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.iter().all(|entry| entry.is_unused())
+  }
 }
 
 impl<L> Table<L> where L: HierarchicalLevel {
   fn next_table_address(&self, index: usize) -> Option<usize> {
     let entry_flags = self[index].flags();
     if entry_flags.contains(EntryFlags::PRESENT) && !entry_flags.contains(EntryFlags::HUGE_PAGE) {
-      let table_address = self as *const _ as usize;
-      Some((table_address << 9) | (index << 12))
+      let physical_address = self[index].pointed_physical_page().unwrap().start_address();
+      if let Some(address) = physical_to_virtual(physical_address) {
+        return Some(address as usize);
+      }
+      Some(recursive_next_table_address(self as *const _ as usize, index))
     }
     else {
       None
@@ -79,13 +96,63 @@ impl<L> Table<L> where L: HierarchicalLevel {
     self.next_table_address(index).map(|address| unsafe { &mut *(address as *mut _) })
   }
 
-  pub fn next_table_create<A>(&mut self, index: usize, allocator: &mut A) -> &mut Table<L::NextLevel> where A: Allocator {
+  // flags are those of the mapping the table is needed for. The CPU only lets user space reach a
+  // page if every entry on the way to it is user accessible, so user mappings make the entry
+  // pointing at the table user accessible too, even if the table already existed.
+  pub fn next_table_create<A>(&mut self, index: usize, flags: EntryFlags, allocator: &mut A) -> &mut Table<L::NextLevel> where A: Allocator {
+    let table_flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | (flags & EntryFlags::USER_ACCESSIBLE);
     if self.next_table(index).is_none() {
-      assert!(!self.entries[index].flags().contains(EntryFlags::HUGE_PAGE), "huge pages are not supported");
+      assert!(!self.entries[index].flags().contains(EntryFlags::HUGE_PAGE), "address is inside a huge page, which must be split first");
       let physical_page = allocator.allocate().expect("out of physical pages");
-      self.entries[index].set(physical_page, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+      self.entries[index].set(physical_page, table_flags);
       self.next_table_mut(index).unwrap().zero();
+      PAGE_TABLE_PAGES.fetch_add(1, Ordering::Relaxed);
+    }
+    else if !self.entries[index].flags().contains(table_flags) {
+      let physical_page = self.entries[index].pointed_physical_page().unwrap();
+      let old_flags = self.entries[index].flags();
+      self.entries[index].set(physical_page, old_flags | table_flags);
     }
     self.next_table_mut(index).unwrap()
   }
+
+  // Unlinks the next table and returns its page to the allocator if it no longer maps anything.
+  // Returns whether the table was freed, in which case this table may have become empty too. This
+  // must not be used on kernel tables, which other address spaces share. recursive_address is where
+  // the next table appears through the recursive entry of the table it belongs to, which may be
+  // cached even if this table was reached through the physical memory map.
+  pub fn free_next_table_if_empty<A>(&mut self, index: usize, recursive_address: VirtualAddress, allocator: &mut A) -> bool where A: Allocator {
+    if !self.next_table(index).map_or(false, |table| table.is_empty()) {
+      return false;
+    }
+    let table_address = self.next_table_address(index).unwrap();
+    let physical_page = self.entries[index].pointed_physical_page().unwrap();
+    self.entries[index].set_unused();
+    tlb::flush(table_address as u64);
+    if table_address as u64 != recursive_address {
+      tlb::flush(recursive_address);
+    }
+    allocator.deallocate(physical_page);
+    PAGE_TABLE_PAGES.fetch_sub(1, Ordering::Relaxed);
+    true
+  }
+
+  // Replaces a huge page entry with the given table, after filling it with entries covering the
+  // same physical memory, each spanning pages_per_entry pages. The entries are only huge pages
+  // themselves if they are not in a P1 table, which the caller indicates through pages_per_entry.
+  // The table must be filled in through some other mapping than the huge page, which could be
+  // what maps it, and the caller must flush the huge page's TLB entry afterwards.
+  pub fn split_huge_entry(&mut self, index: usize, table: &mut Table<L::NextLevel>, table_page: PhysicalPage, pages_per_entry: usize) {
+    let flags = self.entries[index].flags();
+    assert!(flags.contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE), "entry is not a huge page");
+    let start = self.entries[index].pointed_physical_page().unwrap();
+    let entry_flags = if pages_per_entry == 1 { flags - EntryFlags::HUGE_PAGE } else { flags };
+    for (i, entry) in table.entries.iter_mut().enumerate() {
+      entry.set(PhysicalPage { number: start.number + i * pages_per_entry }, entry_flags);
+    }
+
+    let table_flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | (flags & EntryFlags::USER_ACCESSIBLE);
+    self.entries[index].set(table_page, table_flags);
+    PAGE_TABLE_PAGES.fetch_add(1, Ordering::Relaxed);
+  }
 }
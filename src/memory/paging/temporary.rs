@@ -18,12 +18,13 @@ impl TemporaryPage {
 
   pub fn map(&mut self, physical_page: PhysicalPage, active_table: &mut ActivePageTable) -> VirtualAddress {
     assert!(active_table.translate_page(self.page).is_none(), "temporary page is already mapped");
-    active_table.map_to(self.page, physical_page, EntryFlags::WRITABLE, &mut self.allocator);
+    active_table.map_to(self.page, physical_page, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, &mut self.allocator);
     self.page.start_address()
   }
 
   pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-    active_table.unmap(self.page, &mut self.allocator);
+    // The page belongs to whoever mapped it, only the page tables come from the tiny allocator.
+    active_table.unmap_without_free(self.page, &mut self.allocator);
   }
 
   pub fn map_table_physical_page(&mut self, physical_page: PhysicalPage, active_table: &mut ActivePageTable) -> &mut Table<Level1> {
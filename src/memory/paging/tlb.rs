@@ -0,0 +1,89 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64;
+use x86_64::instructions::tlb;
+
+use cpu;
+use super::{VirtualAddress, VirtualPage};
+
+const CR4_PGE: u64 = 1 << 7;
+const CR4_PCIDE: u64 = 1 << 17;
+const CR3_PCID_MASK: u64 = 0xfff;
+// Set when writing CR3 to keep the TLB entries tagged with the new PCID.
+pub const CR3_NO_FLUSH: u64 = 1 << 63;
+
+// Past this many pages, flushing everything is cheaper than flushing pages one at a time.
+const FLUSH_RANGE_LIMIT: usize = 32;
+
+static GLOBAL_PAGES_ENABLED: AtomicBool = AtomicBool::new(false);
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Enables global pages, so that kernel mappings survive address space switches, and PCIDs, so
+// that other address spaces' mappings do too, as far as the CPU supports them.
+pub fn init() {
+  let mut cr4 = cpu::read_cr4();
+  if cpu::has_global_pages() {
+    cr4 |= CR4_PGE;
+    GLOBAL_PAGES_ENABLED.store(true, Ordering::Relaxed);
+  }
+  // The PCID in CR3 has to be 0 when PCIDs are enabled, which it still is from boot. Without
+  // global pages, invlpg would leave stale kernel mappings cached under other PCIDs.
+  if cpu::has_pcid() && global_pages_enabled() {
+    cr4 |= CR4_PCIDE;
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+  }
+  unsafe { cpu::write_cr4(cr4) };
+}
+
+pub fn global_pages_enabled() -> bool {
+  GLOBAL_PAGES_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn pcid_enabled() -> bool {
+  PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn current_pcid() -> u16 {
+  (cpu::read_cr3() & CR3_PCID_MASK) as u16
+}
+
+pub fn flush(address: VirtualAddress) {
+  tlb::flush(x86_64::VirtAddr::new(address));
+}
+
+// Flushes every non-global mapping of the current address space. Unlike tlb::flush_all, this
+// keeps the current PCID.
+pub fn flush_address_space() {
+  unsafe { cpu::write_cr3(cpu::read_cr3() & !CR3_NO_FLUSH) };
+}
+
+// Flushes every mapping, including global ones and those of other address spaces.
+pub fn flush_global() {
+  if global_pages_enabled() {
+    // Toggling PGE flushes the whole TLB, whatever the PCID.
+    let cr4 = cpu::read_cr4();
+    unsafe {
+      cpu::write_cr4(cr4 & !CR4_PGE);
+      cpu::write_cr4(cr4);
+    }
+  }
+  else {
+    flush_address_space();
+  }
+}
+
+pub fn flush_range(start: VirtualPage, end: VirtualPage) {
+  if end.number - start.number + 1 > FLUSH_RANGE_LIMIT {
+    if start.is_kernel() || end.is_kernel() {
+      flush_global();
+    }
+    else {
+      flush_address_space();
+    }
+  }
+  else {
+    for page in VirtualPage::range_inclusive(start, end) {
+      flush(page.start_address());
+    }
+  }
+}
@@ -0,0 +1,67 @@
+use memory::{Allocator, PhysicalPage, PAGE_SIZE};
+use memory::area_allocator::MAX_PHYSICAL_MEMORY;
+
+const MAX_PAGES: usize = (MAX_PHYSICAL_MEMORY / PAGE_SIZE) as usize;
+
+// The number of mappings of each physical page that is shared between address spaces. Pages
+// with a single owner have a count of 0 rather than 1, so that only sharing has to touch this.
+// Like the page allocator's bitmap, this is far too large for the boot stack.
+static mut SHARE_COUNTS: [u16; MAX_PAGES] = [0; MAX_PAGES];
+
+pub struct SharedPages {
+  counts: &'static mut [u16; MAX_PAGES]
+}
+
+impl SharedPages {
+  // Must only be called once, as every SharedPages uses the same counts.
+  pub unsafe fn new() -> SharedPages {
+    SharedPages { counts: &mut SHARE_COUNTS }
+  }
+
+  // Records that the page has been mapped once more. Only address spaces cloned through the
+  // physical memory map share pages.
+  #[cfg(feature = "physical_memory_map")]
+  pub fn share(&mut self, page: &PhysicalPage) {
+    let count = &mut self.counts[page.number];
+    assert!(*count < u16::max_value(), "physical page {:#x} is shared too many times", page.start_address());
+    *count = if *count == 0 { 2 } else { *count + 1 };
+  }
+
+  // Wraps the allocator so that the pages given back to it are only freed once nothing else maps
+  // them. Anything that unmaps pages which may be shared must free them through this.
+  pub fn unsharing<'a, A>(&'a mut self, allocator: &'a mut A) -> Unsharing<'a, A> where A: Allocator {
+    Unsharing { shared_pages: self, allocator }
+  }
+
+  pub fn is_shared(&self, page: &PhysicalPage) -> bool {
+    self.counts[page.number] != 0
+  }
+
+  // Records that one mapping of the page has gone, returning whether that was the last one, in
+  // which case the page can be freed.
+  pub fn release(&mut self, page: &PhysicalPage) -> bool {
+    let count = &mut self.counts[page.number];
+    match *count {
+      0 => true,
+      2 => { *count = 0; false },
+      _ => { *count -= 1; false }
+    }
+  }
+}
+
+pub struct Unsharing<'a, A: 'a + Allocator> {
+  shared_pages: &'a mut SharedPages,
+  allocator: &'a mut A
+}
+
+impl<'a, A> Allocator for Unsharing<'a, A> where A: Allocator {
+  fn allocate(&mut self) -> Option<PhysicalPage> {
+    self.allocator.allocate()
+  }
+
+  fn deallocate(&mut self, page: PhysicalPage) {
+    if self.shared_pages.release(&page) {
+      self.allocator.deallocate(page);
+    }
+  }
+}
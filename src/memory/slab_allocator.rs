@@ -0,0 +1,144 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::cmp::max;
+use core::{mem, ptr, slice};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use memory::PAGE_SIZE;
+use memory::debug_allocator::POISON_FREE;
+use memory::heap_allocator::{align_up, GrowableHeap, HeapStats};
+
+struct FreeObject {
+  next: *mut FreeObject
+}
+
+// A cache of free objects of one size, carved out of page-sized slabs. Objects are aligned to
+// their size, because slabs are page aligned and every size is a power of two.
+struct SizeClass {
+  size: usize,
+  free: *mut FreeObject
+}
+
+// The free list is only ever touched with the size class's lock held.
+unsafe impl Send for SizeClass {}
+
+impl SizeClass {
+  const fn new(size: usize) -> SizeClass {
+    SizeClass { size, free: ptr::null_mut() }
+  }
+
+  unsafe fn pop(&mut self) -> *mut u8 {
+    let object = self.free;
+    if !object.is_null() {
+      self.free = (*object).next;
+      if cfg!(feature = "heap_debug") {
+        self.check_poison(object as *mut u8);
+      }
+    }
+    object as *mut u8
+  }
+
+  // With heap_debug, everything but the free list pointer of a free object should still hold
+  // the poison written when it was freed.
+  unsafe fn check_poison(&self, object: *mut u8) {
+    let header_size = mem::size_of::<FreeObject>();
+    let contents = slice::from_raw_parts(object.offset(header_size as isize), self.size - header_size);
+    if let Some(offset) = contents.iter().position(|&byte| byte != POISON_FREE) {
+      panic!("use after free: the {} byte object at {:#x} was written to at byte {} after being freed",
+             self.size, object as usize, header_size + offset);
+    }
+  }
+
+  unsafe fn push(&mut self, ptr: *mut u8) {
+    let object = ptr as *mut FreeObject;
+    (*object).next = self.free;
+    self.free = object;
+  }
+
+  unsafe fn add_slab(&mut self, slab: *mut u8) {
+    if cfg!(feature = "heap_debug") {
+      ptr::write_bytes(slab, POISON_FREE, PAGE_SIZE as usize);
+    }
+    for i in (0..PAGE_SIZE as usize / self.size).rev() {
+      self.push(slab.offset((i * self.size) as isize));
+    }
+  }
+}
+
+const CLASS_COUNT: usize = 9;
+
+// Defines SIZE_CLASSES, and the empty caches to go with them, from a single list of sizes. The
+// caches can't be built by looping over SIZE_CLASSES, as SlabAllocator::new is a const fn.
+macro_rules! size_classes {
+  ($($size:expr),*) => {
+    const SIZE_CLASSES: [usize; CLASS_COUNT] = [$($size),*];
+
+    const fn empty_classes() -> [Mutex<SizeClass>; CLASS_COUNT] {
+      [$(Mutex::new(SizeClass::new($size))),*]
+    }
+  }
+}
+
+size_classes!(8, 16, 32, 64, 128, 256, 512, 1024, 2048);
+
+// Serves small allocations from per-size slab caches, and larger ones as whole pages from the
+// growable heap underneath. Slabs are never handed back to the heap once they have been created.
+// Like the heap's, the caches' locks are held with interrupts disabled, so that interrupt handlers
+// can allocate.
+pub struct SlabAllocator {
+  classes: [Mutex<SizeClass>; CLASS_COUNT],
+  pages: GrowableHeap
+}
+
+impl SlabAllocator {
+  pub const fn new(pages: GrowableHeap) -> SlabAllocator {
+    SlabAllocator { classes: empty_classes(), pages }
+  }
+
+  // The range must already be reserved as a virtual memory area that nothing else uses.
+  pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+    self.pages.init(heap_start, heap_size);
+  }
+
+  // Slabs count as used in full, whether or not all of their objects are allocated.
+  pub fn stats(&self) -> HeapStats {
+    self.pages.stats()
+  }
+
+  fn size_class(layout: &Layout) -> Option<usize> {
+    let size = max(layout.size(), layout.align());
+    SIZE_CLASSES.iter().position(|&class_size| class_size >= size)
+  }
+
+  fn page_layout(layout: &Layout) -> Layout {
+    let page_size = PAGE_SIZE as usize;
+    unsafe { Layout::from_size_align_unchecked(align_up(layout.size(), page_size), max(layout.align(), page_size)) }
+  }
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    match Self::size_class(&layout) {
+      Some(index) => interrupts::without_interrupts(|| {
+        let mut class = self.classes[index].lock();
+        if class.free.is_null() {
+          let slab = self.pages.alloc(Layout::from_size_align_unchecked(PAGE_SIZE as usize, PAGE_SIZE as usize));
+          if slab.is_null() {
+            return slab;
+          }
+          class.add_slab(slab);
+        }
+        class.pop()
+      }),
+      None => self.pages.alloc(Self::page_layout(&layout))
+    }
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    match Self::size_class(&layout) {
+      Some(index) => interrupts::without_interrupts(|| self.classes[index].lock().push(ptr)),
+      None => self.pages.dealloc(ptr, Self::page_layout(&layout))
+    }
+  }
+}
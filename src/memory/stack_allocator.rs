@@ -1,56 +1,123 @@
-use memory::{Allocator, PAGE_SIZE};
-use memory::paging::{ActivePageTable, EntryFlags, VirtualPageIter, VirtualPage};
+use spin::Mutex;
+
+use memory::{self, Allocator, PAGE_SIZE};
+use memory::free_ranges::FreeRanges;
+use memory::paging::{ActivePageTable, EntryFlags, VirtualAddress, VirtualPageIter, VirtualPage};
+use memory::vma::VmaRegistry;
+
+const MAX_GUARD_PAGES: usize = 64;
+
+// The guard page below each allocated stack, along with the name of that stack, so that the page
+// fault handler can tell a stack overflow apart from any other bad access.
+static GUARD_PAGES: Mutex<[Option<(VirtualPage, &'static str)>; MAX_GUARD_PAGES]> = Mutex::new([None; MAX_GUARD_PAGES]);
+
+// Called from exception handlers, which may have interrupted code holding the lock, so this gives
+// up rather than wait for it.
+pub fn guard_page_owner(address: VirtualAddress) -> Option<&'static str> {
+  let page = VirtualPage::containing_address(address);
+  let guard_pages = GUARD_PAGES.try_lock()?;
+  guard_pages.iter().filter_map(|guard| *guard).find(|&(guard_page, _)| guard_page == page).map(|(_, name)| name)
+}
+
+pub fn register_guard_page(page: VirtualPage, name: &'static str) {
+  let mut guard_pages = GUARD_PAGES.lock();
+  match guard_pages.iter_mut().find(|guard| guard.is_none()) {
+    Some(slot) => *slot = Some((page, name)),
+    None => println!("warning: too many stacks to track the guard page of stack '{}'", name)
+  }
+}
+
+fn unregister_guard_page(page: VirtualPage) {
+  for guard in GUARD_PAGES.lock().iter_mut() {
+    if guard.map_or(false, |(guard_page, _)| guard_page == page) {
+      *guard = None;
+    }
+  }
+}
 
 pub struct StackAllocator {
-  range: VirtualPageIter
+  // Each stack takes a guard page followed by its own pages, which are given back together.
+  pages: FreeRanges,
+  stacks: usize,
+  stack_pages: usize
 }
 
 impl StackAllocator {
   pub fn new(page_range: VirtualPageIter) -> StackAllocator {
-    StackAllocator { range: page_range }
+    StackAllocator { pages: FreeRanges::new(page_range, "stack"), stacks: 0, stack_pages: 0 }
   }
 
-  pub fn alloc_stack<A: Allocator>(&mut self, active_table: &mut ActivePageTable, allocator: &mut A, size_in_pages: usize) -> Option<Stack> {
-    if size_in_pages == 0 { return None; }
+  // The number of stacks currently allocated, and the number of pages they span, not counting
+  // their guard pages.
+  pub fn stacks(&self) -> usize { self.stacks }
+  pub fn stack_pages(&self) -> usize { self.stack_pages }
 
-    let mut range = self.range.clone();
-    let guard_page = range.next();
-    let stack_start = range.next();
-    let stack_end = if size_in_pages == 1 {
-      stack_start
-    }
-    else {
-      range.nth(size_in_pages - 2)
-    };
-
-    match (guard_page, stack_start, stack_end) {
-      (Some(_), Some(start), Some(end)) => {
-        self.range = range;
-
-        for page in VirtualPage::range_inclusive(start, end) {
-          active_table.map(page, EntryFlags::WRITABLE, allocator);
-        }
-
-        let top_of_stack = end.start_address() + PAGE_SIZE;
-        Some(Stack::new(top_of_stack, start.start_address()))
-      },
-      _ => None  // not enough pages
-    }
+  pub fn alloc_stack<A: Allocator>(&mut self, active_table: &mut ActivePageTable, allocator: &mut A, size_in_pages: usize, name: &'static str) -> Option<Stack> {
+    self.take_pages(size_in_pages, name).map(|(start, end)| {
+      for page in VirtualPage::range_inclusive(start, end) {
+        active_table.map(page, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, allocator);
+      }
+      Stack::new(end.start_address() + PAGE_SIZE, start.start_address(), name)
+    })
+  }
+
+  // Like alloc_stack, but the stack's pages are only mapped once they are touched. This must not
+  // be used for stacks that the page fault handler itself might run on.
+  pub fn alloc_lazy_stack(&mut self, vmas: &mut VmaRegistry, size_in_pages: usize, name: &'static str) -> Option<Stack> {
+    self.take_pages(size_in_pages, name).map(|(start, end)| {
+      vmas.reserve(start, end, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, name);
+      Stack::new(end.start_address() + PAGE_SIZE, start.start_address(), name)
+    })
+  }
+
+  // Unmaps the stack's pages, freeing their physical pages, and makes its virtual pages available
+  // for new stacks.
+  pub fn free_stack<A: Allocator>(&mut self, active_table: &mut ActivePageTable, allocator: &mut A, vmas: &mut VmaRegistry, stack: &Stack) {
+    let start = VirtualPage::containing_address(stack.bottom);
+    let end = VirtualPage::containing_address(stack.top - 1);
+    vmas.release(start);
+    // Lazily allocated stacks may not have had all of their pages touched, which unmap_range skips.
+    active_table.unmap_range(start, end, allocator);
+    let guard = start - 1;
+    unregister_guard_page(guard);
+    self.stacks -= 1;
+    self.stack_pages -= ((stack.top - stack.bottom) / PAGE_SIZE) as usize;
+    self.pages.add(guard, end);
+  }
+
+  // Takes a guard page followed by the given number of stack pages, preferring the pages of
+  // previously freed stacks over fresh ones from the range.
+  fn take_pages(&mut self, size_in_pages: usize, name: &'static str) -> Option<(VirtualPage, VirtualPage)> {
+    if size_in_pages == 0 { return None; }
+    let guard = self.pages.take(size_in_pages + 1)?;
+    register_guard_page(guard, name);
+    self.stacks += 1;
+    self.stack_pages += size_in_pages;
+    Some((guard + 1, guard + size_in_pages))
   }
 }
 
 #[derive(Debug)]
 pub struct Stack {
   top: u64,
-  bottom: u64
+  bottom: u64,
+  name: &'static str
 }
 
 impl Stack {
-  fn new(top: u64, bottom: u64) -> Stack {
+  fn new(top: u64, bottom: u64, name: &'static str) -> Stack {
     assert!(top > bottom);
-    Stack { top, bottom }
+    Stack { top, bottom, name }
   }
 
   pub fn top(&self) -> u64 { self.top }
   pub fn bottom(&self) -> u64 { self.bottom }
+  pub fn name(&self) -> &'static str { self.name }
+}
+
+// A Stack must not be dropped while the memory controller is locked.
+impl Drop for Stack {
+  fn drop(&mut self) {
+    memory::controller_for_drop("stack").free_stack(self);
+  }
 }
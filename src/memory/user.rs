@@ -0,0 +1,57 @@
+use core::ptr;
+
+use cpu::UserAccess;
+use memory::PAGE_SIZE;
+use memory::paging::{walk_active_table, EntryFlags, PageWalk, VirtualAddress};
+
+// User space is the lower half of the address space.
+const USER_END: VirtualAddress = 0x0000_8000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+  // The range is not entirely in the lower half.
+  NotUserAddress,
+  // The page at this address is not mapped as user accessible, or for a write, not writable.
+  BadPage(VirtualAddress)
+}
+
+// Checks that every page of the range is mapped in a way that user space itself could access,
+// going by the tables on the way to it as well as its own entry, since the kernel would otherwise
+// fault on it. Copy-on-write pages count as writable, as they are copied when written to.
+fn check_user_range(start: VirtualAddress, len: usize, write: bool) -> Result<(), UserCopyError> {
+  if len == 0 {
+    return Ok(());
+  }
+  let end = start.checked_add(len as u64).ok_or(UserCopyError::NotUserAddress)?;
+  if end > USER_END {
+    return Err(UserCopyError::NotUserAddress);
+  }
+  let mut page_start = start - start % PAGE_SIZE;
+  while page_start < end {
+    let address = if page_start < start { start } else { page_start };
+    match walk_active_table(address) {
+      PageWalk::Mapped { effective_flags, .. } if effective_flags.contains(EntryFlags::USER_ACCESSIBLE) => {
+        if write && !effective_flags.intersects(EntryFlags::WRITABLE | EntryFlags::COPY_ON_WRITE) {
+          return Err(UserCopyError::BadPage(address));
+        }
+      },
+      _ => return Err(UserCopyError::BadPage(address))
+    }
+    page_start += PAGE_SIZE;
+  }
+  Ok(())
+}
+
+pub fn copy_from_user(destination: &mut [u8], source: VirtualAddress) -> Result<(), UserCopyError> {
+  check_user_range(source, destination.len(), false)?;
+  let _access = UserAccess::begin();
+  unsafe { ptr::copy_nonoverlapping(source as *const u8, destination.as_mut_ptr(), destination.len()) };
+  Ok(())
+}
+
+pub fn copy_to_user(destination: VirtualAddress, source: &[u8]) -> Result<(), UserCopyError> {
+  check_user_range(destination, source.len(), true)?;
+  let _access = UserAccess::begin();
+  unsafe { ptr::copy_nonoverlapping(source.as_ptr(), destination as *mut u8, source.len()) };
+  Ok(())
+}
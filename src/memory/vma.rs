@@ -0,0 +1,72 @@
+use memory::paging::{EntryFlags, VirtualPage};
+
+const MAX_AREAS: usize = 64;
+
+// A range of virtual memory that is backed by zeroed pages on first access, rather than up front.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualMemoryArea {
+  start: VirtualPage,
+  end: VirtualPage,
+  flags: EntryFlags,
+  name: &'static str
+}
+
+impl VirtualMemoryArea {
+  pub fn contains(&self, page: VirtualPage) -> bool {
+    page >= self.start && page <= self.end
+  }
+
+  pub fn start(&self) -> VirtualPage { self.start }
+  pub fn end(&self) -> VirtualPage { self.end }
+  pub fn flags(&self) -> EntryFlags { self.flags }
+  pub fn name(&self) -> &'static str { self.name }
+
+  pub fn is_backed(&self) -> bool { !self.flags.is_empty() }
+}
+
+// This is a fixed-size table rather than a heap collection, as the heap itself is demand paged.
+pub struct VmaRegistry {
+  areas: [Option<VirtualMemoryArea>; MAX_AREAS]
+}
+
+impl VmaRegistry {
+  pub fn new() -> VmaRegistry {
+    VmaRegistry { areas: [None; MAX_AREAS] }
+  }
+
+  pub fn reserve(&mut self, start: VirtualPage, end: VirtualPage, flags: EntryFlags, name: &'static str) {
+    assert!(flags.contains(EntryFlags::WRITABLE), "virtual memory area '{}' is zero-filled so must be writable", name);
+    self.insert(VirtualMemoryArea { start, end, flags, name });
+  }
+
+  // Reserves a range that the page fault handler never backs with pages, so that nothing else can
+  // be reserved there. This is for guard areas, and for the heap, which is backed separately.
+  pub fn reserve_unbacked(&mut self, start: VirtualPage, end: VirtualPage, name: &'static str) {
+    self.insert(VirtualMemoryArea { start, end, flags: EntryFlags::empty(), name });
+  }
+
+  fn insert(&mut self, area: VirtualMemoryArea) {
+    assert!(area.start <= area.end, "virtual memory area '{}' is empty", area.name);
+    if let Some(other) = self.iter().find(|other| other.start <= area.end && area.start <= other.end) {
+      panic!("virtual memory area '{}' overlaps '{}'", area.name, other.name);
+    }
+    match self.areas.iter_mut().find(|slot| slot.is_none()) {
+      Some(slot) => *slot = Some(area),
+      None => panic!("too many virtual memory areas to reserve '{}'", area.name)
+    }
+  }
+
+  pub fn release(&mut self, start: VirtualPage) -> Option<VirtualMemoryArea> {
+    self.areas.iter_mut()
+              .find(|area| area.map_or(false, |area| area.start == start))
+              .and_then(|area| area.take())
+  }
+
+  pub fn find(&self, page: VirtualPage) -> Option<VirtualMemoryArea> {
+    self.iter().find(|area| area.contains(page))
+  }
+
+  pub fn iter<'a>(&'a self) -> impl Iterator<Item = VirtualMemoryArea> + 'a {
+    self.areas.iter().filter_map(|area| *area)
+  }
+}
@@ -52,10 +52,23 @@ pub struct Writer {
   buffer: Unique<Buffer>
 }
 
+const BACKSPACE: u8 = 0x08;
+
+// The VGA text mode font is code page 437, which has ASCII and a few other characters that the
+// keyboard layouts can type. Anything else is shown as a block.
+fn code_page_437(c: char) -> u8 {
+  match c {
+    '\x08' | '\n' | ' '...'~' => c as u8,
+    'ü' => 0x81, 'ä' => 0x84, 'Ä' => 0x8e, 'ö' => 0x94, 'Ö' => 0x99, 'Ü' => 0x9a, '£' => 0x9c,
+    '¬' => 0xaa, 'ß' => 0xe1, 'µ' => 0xe6, '°' => 0xf8, '²' => 0xfd, '§' => 0x15,
+    _ => 0xfe
+  }
+}
+
 impl fmt::Write for Writer {
   fn write_str(&mut self, s: &str) -> fmt::Result {
-    for byte in s.bytes() {
-      self.write_byte(byte);
+    for c in s.chars() {
+      self.write_byte(code_page_437(c));
     }
     Ok(())
   }
@@ -65,6 +78,14 @@ impl Writer {
   fn write_byte(&mut self, byte: u8) {
     match byte {
       b'\n' => self.new_line(),
+      BACKSPACE => {
+        if self.pos > 0 {
+          self.pos -= 1;
+          let pos = self.pos;
+          let colour_code = self.colour_code;
+          self.buffer().cells[BUF_HEIGHT - 1][pos].write(Cell { character: b' ', colour_code });
+        }
+      },
       byte => {
         if self.pos >= BUF_WIDTH {
           self.new_line();
@@ -110,7 +131,7 @@ impl Writer {
 pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
   pos: 0,
   colour_code: ColourCode::new(Colour::LightGreen, Colour::Black),
-  buffer: unsafe { Unique::new_unchecked(0xb8000 as *mut _) }
+  buffer: unsafe { Unique::new_unchecked((::KERNEL_OFFSET + 0xb8000) as *mut _) }
 });
 
 macro_rules! print {
@@ -119,11 +140,19 @@ macro_rules! print {
   });
 }
 
+#[cfg(not(test))]
 pub fn print(args: fmt::Arguments) {
   use core::fmt::Write;
   WRITER.lock().write_fmt(args).unwrap();
 }
 
+// There is no VGA buffer to write to when running unit tests on the host.
+#[cfg(test)]
+pub fn print(args: fmt::Arguments) {
+  use std::io::Write;
+  let _ = ::std::io::stdout().write_fmt(args);
+}
+
 macro_rules! println {
   ($fmt:expr) => (print!(concat!($fmt, "\n")));
   ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));